@@ -0,0 +1,133 @@
+use std::net::IpAddr;
+
+use ipnet::IpNet;
+
+use crate::{
+    commons::Handler, request_processing::client_address::ClientAddr,
+    response_building::forbidden,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Allow,
+    Deny,
+}
+
+#[derive(Debug, Clone)]
+pub struct AccessRule {
+    pub access: Access,
+    pub network: IpNet,
+}
+
+//Ordered CIDR rules. The first rule containing the address decides, otherwise the default applies.
+#[derive(Debug, Clone)]
+pub struct AccessControl {
+    rules: Vec<AccessRule>,
+    default: Access,
+}
+
+impl AccessControl {
+    pub fn allow_by_default() -> AccessControl {
+        AccessControl {
+            rules: Vec::new(),
+            default: Access::Allow,
+        }
+    }
+
+    pub fn deny_by_default() -> AccessControl {
+        AccessControl {
+            rules: Vec::new(),
+            default: Access::Deny,
+        }
+    }
+
+    pub fn allow(self, cidr: &str) -> Result<AccessControl, ipnet::AddrParseError> {
+        Ok(self.rule(Access::Allow, parse_network(cidr)?))
+    }
+
+    pub fn deny(self, cidr: &str) -> Result<AccessControl, ipnet::AddrParseError> {
+        Ok(self.rule(Access::Deny, parse_network(cidr)?))
+    }
+
+    pub fn rule(mut self, access: Access, network: IpNet) -> AccessControl {
+        self.rules.push(AccessRule { access, network });
+        self
+    }
+
+    pub fn evaluate(&self, ip: IpAddr) -> Access {
+        //Dual stack sockets report IPv4 clients as ::ffff:a.b.c.d
+        let ip = ip.to_canonical();
+        self.rules
+            .iter()
+            .find(|rule| rule.network.contains(&ip))
+            .map(|rule| rule.access)
+            .unwrap_or(self.default)
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        self.evaluate(ip) == Access::Allow
+    }
+}
+
+//Accepts CIDR notation or a bare address, which is treated as a single host.
+pub fn parse_network(cidr: &str) -> Result<IpNet, ipnet::AddrParseError> {
+    let cidr = cidr.trim();
+    match cidr.parse::<IpNet>() {
+        Ok(network) => Ok(network.trunc()),
+        Err(e) => match cidr.parse::<IpAddr>() {
+            Ok(ip) => Ok(IpNet::from(ip)),
+            Err(_) => Err(e),
+        },
+    }
+}
+
+//Per route check. Requests without a resolved client address (e.g. not served over TCP) are refused.
+pub fn check_access(
+    request_parts: &hyper::http::request::Parts,
+    access_control: &AccessControl,
+) -> Handler {
+    match request_parts.extensions.get::<ClientAddr>() {
+        Some(ClientAddr(ip)) if access_control.permits(*ip) => Handler::Continue,
+        _ => Handler::ImmediateReturn(forbidden()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let access_control = AccessControl::deny_by_default()
+            .deny("192.168.1.66")
+            .unwrap()
+            .allow("192.168.1.0/24")
+            .unwrap()
+            .allow("fd00::/8")
+            .unwrap();
+
+        assert!(access_control.permits("192.168.1.10".parse().unwrap()));
+        assert!(!access_control.permits("192.168.1.66".parse().unwrap()));
+        assert!(!access_control.permits("10.0.0.1".parse().unwrap()));
+        assert!(access_control.permits("fd12:3456::1".parse().unwrap()));
+        assert!(!access_control.permits("2001:db8::1".parse().unwrap()));
+    }
+
+    #[test]
+    fn mapped_ipv4_addresses_match_ipv4_rules() {
+        let access_control = AccessControl::deny_by_default()
+            .allow("10.0.0.0/8")
+            .unwrap();
+        assert!(access_control.permits("::ffff:10.1.2.3".parse().unwrap()));
+    }
+
+    #[test]
+    fn parse_network_rejects_garbage() {
+        assert!(parse_network("10.0.0.0/33").is_err());
+        assert!(parse_network("not an address").is_err());
+        assert_eq!(
+            parse_network("10.1.2.3/8").unwrap(),
+            "10.0.0.0/8".parse::<IpNet>().unwrap()
+        );
+    }
+}
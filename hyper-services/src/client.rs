@@ -0,0 +1,508 @@
+use std::time::Duration;
+
+use http_body_util::{BodyExt, Limited};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Method, Request, Response, StatusCode, Uri,
+};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector, HttpsConnectorBuilder};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::TokioExecutor,
+};
+use rand::Rng;
+use rustls::pki_types::CertificateDer;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    commons::{HandlerBody, HandlerError},
+    response_building::{bytes_to_boxed_body, empty_body},
+    service::certificates::TlsCerts,
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_POOL_IDLE_TIMEOUT: Duration = Duration::from_secs(90);
+
+//Error bodies longer than this are cut off in UnexpectedStatus.
+const MAX_ERROR_BODY: usize = 4096;
+//Success bodies the JSON helpers will buffer.
+pub const DEFAULT_MAX_RESPONSE_SIZE: usize = 10 * 1024 * 1024;
+
+//Retries only apply to idempotent methods, whose bodies can be sent again.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    pub max_retries: u32,
+    pub initial_backoff: Duration,
+    pub max_backoff: Duration,
+    //Responses with these statuses are retried, as are connection failures and timeouts.
+    pub retry_statuses: Vec<StatusCode>,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 2,
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_secs(5),
+            retry_statuses: vec![
+                StatusCode::TOO_MANY_REQUESTS,
+                StatusCode::BAD_GATEWAY,
+                StatusCode::SERVICE_UNAVAILABLE,
+                StatusCode::GATEWAY_TIMEOUT,
+            ],
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn none() -> RetryPolicy {
+        RetryPolicy {
+            max_retries: 0,
+            ..Default::default()
+        }
+    }
+
+    //Exponential backoff with full jitter, so clients that failed together don't retry together.
+    fn backoff(&self, attempt: u32) -> Duration {
+        let ceiling = self
+            .initial_backoff
+            .saturating_mul(2u32.saturating_pow(attempt))
+            .min(self.max_backoff);
+        ceiling.mul_f64(rand::rng().random_range(0.0..=1.0))
+    }
+}
+
+//Returned (boxed) by the JSON helpers when the server answers with a non-success status.
+#[derive(Debug)]
+pub struct UnexpectedStatus {
+    pub status: StatusCode,
+    pub body: String,
+}
+
+impl std::fmt::Display for UnexpectedStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Server answered {}: {}", self.status, self.body)
+    }
+}
+
+impl std::error::Error for UnexpectedStatus {}
+
+//Pooled HTTP and HTTPS client. Cheap to clone, clones share the pool.
+#[derive(Clone)]
+pub struct HttpClient {
+    client: Client<HttpsConnector<HttpConnector>, HandlerBody>,
+    default_headers: HeaderMap,
+    timeout: Duration,
+    retry: RetryPolicy,
+    max_response_size: usize,
+}
+
+pub struct HttpClientBuilder {
+    root_certificates: Option<Vec<CertificateDer<'static>>>,
+    client_auth: Option<TlsCerts>,
+    tls_config: Option<rustls::ClientConfig>,
+    default_headers: HeaderMap,
+    timeout: Duration,
+    connect_timeout: Duration,
+    pool_idle_timeout: Duration,
+    pool_max_idle_per_host: usize,
+    retry: RetryPolicy,
+    max_response_size: usize,
+}
+
+impl HttpClient {
+    pub fn builder() -> HttpClientBuilder {
+        HttpClientBuilder {
+            root_certificates: None,
+            client_auth: None,
+            tls_config: None,
+            default_headers: HeaderMap::new(),
+            timeout: DEFAULT_TIMEOUT,
+            connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+            pool_idle_timeout: DEFAULT_POOL_IDLE_TIMEOUT,
+            pool_max_idle_per_host: usize::MAX,
+            retry: RetryPolicy::default(),
+            max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+        }
+    }
+
+    //Native roots, default settings.
+    pub fn new() -> Result<HttpClient, HandlerError> {
+        HttpClient::builder().build()
+    }
+
+    //Sends a request once. The timeout covers the response head; the body streams afterwards.
+    pub async fn request(&self, mut request: Request<HandlerBody>) -> Result<Response<Incoming>, HandlerError> {
+        for (name, value) in &self.default_headers {
+            if !request.headers().contains_key(name) {
+                request.headers_mut().insert(name, value.clone());
+            }
+        }
+        match tokio::time::timeout(self.timeout, self.client.request(request)).await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(e)) => Err(Box::new(e)),
+            Err(_) => Err(format!("Request timed out after {:?}.", self.timeout).into()),
+        }
+    }
+
+    //Sends a buffered body, retrying idempotent methods according to the retry policy.
+    pub async fn send(
+        &self,
+        method: Method,
+        uri: &str,
+        headers: HeaderMap,
+        body: Bytes,
+    ) -> Result<Response<Incoming>, HandlerError> {
+        let uri: Uri = uri.parse()?;
+        let retries = match method.is_idempotent() {
+            true => self.retry.max_retries,
+            false => 0,
+        };
+        let mut attempt = 0;
+        loop {
+            let mut request = Request::builder()
+                .method(method.clone())
+                .uri(uri.clone())
+                .body(match body.is_empty() {
+                    true => empty_body(),
+                    false => bytes_to_boxed_body(body.clone()),
+                })?;
+            *request.headers_mut() = headers.clone();
+
+            let outcome = self.request(request).await;
+            let retry_after = match &outcome {
+                Ok(response) if self.retry.retry_statuses.contains(&response.status()) => {
+                    Some(retry_after(response.headers()))
+                }
+                Ok(_) => None,
+                Err(_) => Some(None),
+            };
+            match retry_after {
+                Some(retry_after) if attempt < retries => {
+                    let delay = retry_after
+                        .unwrap_or_else(|| self.retry.backoff(attempt))
+                        .min(self.retry.max_backoff);
+                    match &outcome {
+                        Ok(response) => tracing::debug!(%uri, status = %response.status(), attempt, ?delay, "Retrying request."),
+                        Err(e) => tracing::debug!(%uri, error = %e, attempt, ?delay, "Retrying request."),
+                    }
+                    attempt += 1;
+                    tokio::time::sleep(delay).await;
+                }
+                _ => return outcome,
+            }
+        }
+    }
+
+    pub async fn get_json<T: DeserializeOwned>(&self, uri: &str) -> Result<T, HandlerError> {
+        let response = self.send(Method::GET, uri, json_headers(), Bytes::new()).await?;
+        read_json(response, self.max_response_size).await
+    }
+
+    pub async fn post_json<B: Serialize, T: DeserializeOwned>(&self, uri: &str, body: &B) -> Result<T, HandlerError> {
+        self.send_json(Method::POST, uri, body).await
+    }
+
+    pub async fn put_json<B: Serialize, T: DeserializeOwned>(&self, uri: &str, body: &B) -> Result<T, HandlerError> {
+        self.send_json(Method::PUT, uri, body).await
+    }
+
+    pub async fn patch_json<B: Serialize, T: DeserializeOwned>(&self, uri: &str, body: &B) -> Result<T, HandlerError> {
+        self.send_json(Method::PATCH, uri, body).await
+    }
+
+    //Fails with UnexpectedStatus unless the response is a success. The body is discarded.
+    pub async fn delete(&self, uri: &str) -> Result<(), HandlerError> {
+        let response = self.send(Method::DELETE, uri, HeaderMap::new(), Bytes::new()).await?;
+        check_status(response, self.max_response_size).await.map(|_| ())
+    }
+
+    pub async fn send_json<B: Serialize, T: DeserializeOwned>(
+        &self,
+        method: Method,
+        uri: &str,
+        body: &B,
+    ) -> Result<T, HandlerError> {
+        let mut headers = json_headers();
+        headers.insert(header::CONTENT_TYPE, HeaderValue::from_static("application/json"));
+        let body = Bytes::from(serde_json::to_vec(body)?);
+        let response = self.send(method, uri, headers, body).await?;
+        read_json(response, self.max_response_size).await
+    }
+}
+
+impl HttpClientBuilder {
+    //Replaces the native root certificates, e.g. to trust a private CA.
+    pub fn with_root_certificates(mut self, certificates: Vec<CertificateDer<'static>>) -> HttpClientBuilder {
+        self.root_certificates = Some(certificates);
+        self
+    }
+
+    //Presents a client certificate for mutual TLS.
+    pub fn with_client_auth(mut self, certs: TlsCerts) -> HttpClientBuilder {
+        self.client_auth = Some(certs);
+        self
+    }
+
+    //Overrides root certificates and client auth entirely.
+    pub fn with_tls_config(mut self, tls_config: rustls::ClientConfig) -> HttpClientBuilder {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    //Added to every request that doesn't set the header itself.
+    pub fn with_default_header(mut self, name: HeaderName, value: HeaderValue) -> HttpClientBuilder {
+        self.default_headers.insert(name, value);
+        self
+    }
+
+    pub fn with_timeout(mut self, timeout: Duration) -> HttpClientBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    pub fn with_connect_timeout(mut self, connect_timeout: Duration) -> HttpClientBuilder {
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    pub fn with_pool(mut self, idle_timeout: Duration, max_idle_per_host: usize) -> HttpClientBuilder {
+        self.pool_idle_timeout = idle_timeout;
+        self.pool_max_idle_per_host = max_idle_per_host;
+        self
+    }
+
+    pub fn with_retry(mut self, retry: RetryPolicy) -> HttpClientBuilder {
+        self.retry = retry;
+        self
+    }
+
+    //Larger bodies make the JSON helpers fail instead of buffering them.
+    pub fn with_max_response_size(mut self, max_response_size: usize) -> HttpClientBuilder {
+        self.max_response_size = max_response_size;
+        self
+    }
+
+    pub fn build(self) -> Result<HttpClient, HandlerError> {
+        let tls_config = match self.tls_config {
+            Some(tls_config) => tls_config,
+            None => {
+                let builder = rustls::ClientConfig::builder();
+                let builder = match self.root_certificates {
+                    Some(certificates) => {
+                        let mut roots = rustls::RootCertStore::empty();
+                        for certificate in certificates {
+                            roots.add(certificate)?;
+                        }
+                        builder.with_root_certificates(roots)
+                    }
+                    None => builder.with_native_roots()?,
+                };
+                match self.client_auth {
+                    Some(certs) => builder.with_client_auth_cert(certs.certs, certs.keys)?,
+                    None => builder.with_no_client_auth(),
+                }
+            }
+        };
+
+        let mut client = Client::builder(TokioExecutor::new());
+        client
+            .pool_idle_timeout(self.pool_idle_timeout)
+            .pool_max_idle_per_host(self.pool_max_idle_per_host);
+        Ok(HttpClient {
+            client: client.build(https_connector(tls_config, Some(self.connect_timeout))),
+            default_headers: self.default_headers,
+            timeout: self.timeout,
+            retry: self.retry,
+            max_response_size: self.max_response_size,
+        })
+    }
+}
+
+//Connector for both schemes. Shared with the reverse proxy.
+pub(crate) fn https_connector(
+    tls_config: rustls::ClientConfig,
+    connect_timeout: Option<Duration>,
+) -> HttpsConnector<HttpConnector> {
+    let mut http = HttpConnector::new();
+    http.enforce_http(false);
+    http.set_connect_timeout(connect_timeout);
+    HttpsConnectorBuilder::new()
+        .with_tls_config(tls_config)
+        .https_or_http()
+        .enable_http1()
+        .wrap_connector(http)
+}
+
+//Deserializes a success response of at most max_size bytes, or fails with UnexpectedStatus.
+pub async fn read_json<T: DeserializeOwned>(response: Response<Incoming>, max_size: usize) -> Result<T, HandlerError> {
+    let body = check_status(response, max_size).await?;
+    Ok(serde_json::from_slice(&body)?)
+}
+
+async fn check_status(response: Response<Incoming>, max_size: usize) -> Result<Bytes, HandlerError> {
+    let status = response.status();
+    if !status.is_success() {
+        return Err(Box::new(UnexpectedStatus {
+            status,
+            body: String::from_utf8_lossy(&error_body(response.into_body()).await).to_string(),
+        }));
+    }
+    match Limited::new(response.into_body(), max_size).collect().await {
+        Ok(body) => Ok(body.to_bytes()),
+        Err(e) if e.is::<http_body_util::LengthLimitError>() => {
+            Err(format!("Response body is larger than {} bytes.", max_size).into())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+//Reads no more than MAX_ERROR_BODY bytes. The rest is left unread and the connection isn't reused.
+async fn error_body(mut body: Incoming) -> Vec<u8> {
+    let mut collected = Vec::new();
+    while collected.len() < MAX_ERROR_BODY {
+        match body.frame().await {
+            Some(Ok(frame)) => {
+                if let Some(data) = frame.data_ref() {
+                    collected.extend_from_slice(data);
+                }
+            }
+            _ => break,
+        }
+    }
+    collected.truncate(MAX_ERROR_BODY);
+    collected
+}
+
+fn json_headers() -> HeaderMap {
+    let mut headers = HeaderMap::new();
+    headers.insert(header::ACCEPT, HeaderValue::from_static("application/json"));
+    headers
+}
+
+//Only the delay-seconds form is used; HTTP dates fall back to the backoff.
+fn retry_after(headers: &HeaderMap) -> Option<Duration> {
+    headers
+        .get(header::RETRY_AFTER)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.trim().parse::<u64>().ok())
+        .map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        convert::Infallible,
+        sync::{
+            atomic::{AtomicUsize, Ordering},
+            Arc,
+        },
+    };
+
+    use http_body_util::Full;
+    use hyper::{server::conn::http1, service::service_fn};
+    use hyper_util::rt::TokioIo;
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    //Answers 503 to the first failures requests, then echoes method and body as JSON.
+    async fn flaky_server(failures: usize) -> (String, Arc<AtomicUsize>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let requests = Arc::new(AtomicUsize::new(0));
+        let counter = requests.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let counter = counter.clone();
+                let service = service_fn(move |request: Request<Incoming>| {
+                    let attempt = counter.fetch_add(1, Ordering::SeqCst);
+                    async move {
+                        let response = match (attempt < failures, request.uri().path()) {
+                            (true, _) => Response::builder()
+                                .status(StatusCode::SERVICE_UNAVAILABLE)
+                                .header(header::RETRY_AFTER, "0")
+                                .body(Full::new(Bytes::from("busy")))
+                                .unwrap(),
+                            (false, "/large") => Response::new(Full::new(Bytes::from(vec![b' '; 2048]))),
+                            (false, _) => {
+                                let method = request.method().to_string();
+                                let body = request.into_body().collect().await.unwrap().to_bytes();
+                                let body = String::from_utf8(body.to_vec()).unwrap();
+                                let json = serde_json::json!({ "method": method, "body": body });
+                                Response::new(Full::new(Bytes::from(json.to_string())))
+                            }
+                        };
+                        Ok::<_, Infallible>(response)
+                    }
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service));
+            }
+        });
+        (format!("http://{}", address), requests)
+    }
+
+    fn client(max_retries: u32) -> HttpClient {
+        HttpClient::builder()
+            .with_root_certificates(Vec::new())
+            .with_retry(RetryPolicy {
+                max_retries,
+                ..Default::default()
+            })
+            .with_max_response_size(1024)
+            .build()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn retries_idempotent_requests() {
+        let (base, requests) = flaky_server(2).await;
+        let echoed: serde_json::Value = client(2).get_json(&format!("{}/items", base)).await.unwrap();
+        assert_eq!(echoed["method"], "GET");
+        assert_eq!(requests.load(Ordering::SeqCst), 3);
+
+        let (base, requests) = flaky_server(2).await;
+        let error = client(1).get_json::<serde_json::Value>(&base).await.unwrap_err();
+        let error = error.downcast_ref::<UnexpectedStatus>().unwrap();
+        assert_eq!(error.status, StatusCode::SERVICE_UNAVAILABLE);
+        assert_eq!(error.body, "busy");
+        assert_eq!(requests.load(Ordering::SeqCst), 2);
+
+        let (base, requests) = flaky_server(1).await;
+        let result = client(2).post_json::<_, serde_json::Value>(&base, &serde_json::json!({})).await;
+        assert!(result.is_err());
+        assert_eq!(requests.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn sends_and_reads_json() {
+        let (base, _) = flaky_server(0).await;
+        let echoed: serde_json::Value =
+            client(0).put_json(&format!("{}/items/1", base), &serde_json::json!({ "name": "a" })).await.unwrap();
+        assert_eq!(echoed, serde_json::json!({ "method": "PUT", "body": "{\"name\":\"a\"}" }));
+
+        let error = client(0).get_json::<serde_json::Value>(&format!("{}/large", base)).await.unwrap_err();
+        assert_eq!(error.to_string(), "Response body is larger than 1024 bytes.");
+    }
+
+    #[test]
+    fn backs_off_exponentially_within_bounds() {
+        let policy = RetryPolicy {
+            initial_backoff: Duration::from_millis(100),
+            max_backoff: Duration::from_millis(350),
+            ..Default::default()
+        };
+        for _ in 0..100 {
+            assert!(policy.backoff(0) <= Duration::from_millis(100));
+            assert!(policy.backoff(1) <= Duration::from_millis(200));
+            assert!(policy.backoff(5) <= Duration::from_millis(350));
+        }
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::RETRY_AFTER, "3".parse().unwrap());
+        assert_eq!(retry_after(&headers), Some(Duration::from_secs(3)));
+    }
+}
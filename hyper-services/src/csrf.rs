@@ -0,0 +1,406 @@
+use base64::Engine;
+use hyper::{
+    header::{self, HeaderValue},
+    http::request::Parts,
+    HeaderMap, Method, Response, StatusCode, Uri,
+};
+
+use crate::{
+    commons::{Handler, HandlerBody},
+    generic_json_error::generic_json_error_with_status,
+    service::connection::ConnectionInfo,
+};
+
+pub const HEADER_X_CSRF_TOKEN: &str = "X-CSRF-Token";
+pub const DEFAULT_COOKIE_NAME: &str = "csrf_token";
+pub const DEFAULT_FORM_FIELD: &str = "csrf_token";
+const DEFAULT_FAILURE_MESSAGE: &str = "CSRF check failed.";
+const HEADER_X_FORWARDED_PROTO: &str = "X-Forwarded-Proto";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn generate() -> CsrfToken {
+        let bytes: [u8; 32] = rand::random();
+        CsrfToken(base64::engine::general_purpose::URL_SAFE_NO_PAD.encode(bytes))
+    }
+
+    pub fn from_string(token: String) -> CsrfToken {
+        CsrfToken(token)
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+//Checks unsafe requests against a token and, unless disabled, their Origin or Referer.
+//Double submit: the token lives in a cookie the page echoes back in a header or form field.
+//Synchronizer: the caller keeps the token in its session and passes it to check_synchronizer.
+#[derive(Debug, Clone)]
+pub struct CsrfProtection {
+    pub cookie_name: String,
+    pub header_name: String,
+    pub form_field: String,
+    //Marks the cookie Secure. Turn off only for plain HTTP development servers.
+    pub secure_cookie: bool,
+    pub check_origin: bool,
+    //Rejects unsafe requests that carry neither Origin nor Referer.
+    pub require_origin: bool,
+    //Origins besides the request's own host, lowercase without a trailing slash, e.g. "https://app.example.com".
+    pub trusted_origins: Vec<String>,
+    pub failure_message: String,
+}
+
+impl Default for CsrfProtection {
+    fn default() -> Self {
+        CsrfProtection {
+            cookie_name: DEFAULT_COOKIE_NAME.to_string(),
+            header_name: HEADER_X_CSRF_TOKEN.to_string(),
+            form_field: DEFAULT_FORM_FIELD.to_string(),
+            secure_cookie: true,
+            check_origin: true,
+            require_origin: false,
+            trusted_origins: Vec::new(),
+            failure_message: DEFAULT_FAILURE_MESSAGE.to_string(),
+        }
+    }
+}
+
+impl CsrfProtection {
+    pub fn new() -> CsrfProtection {
+        CsrfProtection::default()
+    }
+
+    pub fn with_trusted_origin(mut self, origin: &str) -> CsrfProtection {
+        self.trusted_origins.push(origin.trim_end_matches('/').to_ascii_lowercase());
+        self
+    }
+
+    pub fn with_failure_message(mut self, message: &str) -> CsrfProtection {
+        self.failure_message = message.to_string();
+        self
+    }
+
+    //The token from the cookie, or a new one to send with set_token_cookie.
+    pub fn token_for(&self, request_headers: &HeaderMap) -> CsrfToken {
+        self.cookie_token(request_headers).unwrap_or_else(CsrfToken::generate)
+    }
+
+    //Readable by scripts on purpose, so they can echo it in the header.
+    pub fn set_token_cookie<B>(&self, mut response: Response<B>, token: &CsrfToken) -> Response<B> {
+        let mut cookie = format!("{}={}; Path=/; SameSite=Strict", self.cookie_name, token.as_str());
+        if self.secure_cookie {
+            cookie.push_str("; Secure");
+        }
+        match HeaderValue::from_str(&cookie) {
+            Ok(value) => {
+                response.headers_mut().append(header::SET_COOKIE, value);
+            }
+            Err(_) => tracing::warn!(cookie = self.cookie_name, "CSRF cookie isn't a valid header."),
+        }
+        response
+    }
+
+    pub fn hidden_input(&self, token: &CsrfToken) -> String {
+        format!(
+            "<input type=\"hidden\" name=\"{}\" value=\"{}\">",
+            escape_html(&self.form_field),
+            escape_html(token.as_str())
+        )
+    }
+
+    //Pass the body of form posts as form_body, the header is used otherwise.
+    pub fn check_double_submit(&self, request_parts: &Parts, form_body: Option<&[u8]>) -> Handler {
+        let expected = self.cookie_token(&request_parts.headers).map(|token| token.0).unwrap_or_default();
+        self.check(request_parts, &expected, form_body)
+    }
+
+    pub fn check_synchronizer(&self, request_parts: &Parts, session_token: &str, form_body: Option<&[u8]>) -> Handler {
+        self.check(request_parts, session_token, form_body)
+    }
+
+    pub fn failure_response(&self) -> Response<HandlerBody> {
+        generic_json_error_with_status(StatusCode::FORBIDDEN, &self.failure_message)
+    }
+
+    fn check(&self, request_parts: &Parts, expected: &str, form_body: Option<&[u8]>) -> Handler {
+        if is_safe_method(&request_parts.method) {
+            return Handler::Continue;
+        }
+        if self.check_origin && !self.origin_allowed(request_parts) {
+            tracing::warn!(method = %request_parts.method, uri = %request_parts.uri, "CSRF origin check failed.");
+            return Handler::ImmediateReturn(self.failure_response());
+        }
+        let submitted = match form_body {
+            Some(body) => form_value(body, &self.form_field),
+            None => request_parts
+                .headers
+                .get(self.header_name.as_str())
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string()),
+        };
+        match submitted {
+            Some(submitted) if !expected.is_empty() && constant_time_eq(submitted.as_bytes(), expected.as_bytes()) => {
+                Handler::Continue
+            }
+            _ => {
+                tracing::warn!(method = %request_parts.method, uri = %request_parts.uri, "CSRF token check failed.");
+                Handler::ImmediateReturn(self.failure_response())
+            }
+        }
+    }
+
+    fn cookie_token(&self, headers: &HeaderMap) -> Option<CsrfToken> {
+        headers
+            .get_all(header::COOKIE)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|cookies| cookies.split(';'))
+            .filter_map(|cookie| cookie.trim().split_once('='))
+            .find(|(name, _)| *name == self.cookie_name)
+            .map(|(_, value)| CsrfToken(value.to_string()))
+            .filter(|token| !token.0.is_empty())
+    }
+
+    //Compares scheme and authority of Origin, or of Referer if there's no Origin, with the request's own origin and the
+    //trusted origins. The own host comes from the Host header, or the URI for HTTP/2, and the own scheme from the
+    //connection, see own_scheme. Default ports don't count.
+    fn origin_allowed(&self, request_parts: &Parts) -> bool {
+        let headers = &request_parts.headers;
+        let origin = headers
+            .get(header::ORIGIN)
+            .or_else(|| headers.get(header::REFERER))
+            .and_then(|value| value.to_str().ok());
+        let origin = match origin {
+            //Sent by privacy-sensitive contexts, it can't be trusted.
+            Some("null") => return false,
+            Some(origin) => origin,
+            None => return !self.require_origin,
+        };
+        let (scheme, authority) = match split_origin(origin) {
+            Some(origin) => origin,
+            None => return false,
+        };
+        let own_host = headers
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .or_else(|| request_parts.uri.authority().map(|authority| authority.as_str()));
+        if scheme == own_scheme(request_parts)
+            && own_host.map(|host| without_default_port(&scheme, host)).as_deref() == Some(authority.as_str())
+        {
+            return true;
+        }
+        self.trusted_origins
+            .iter()
+            .filter_map(|trusted| split_origin(trusted))
+            .any(|trusted| trusted == (scheme.clone(), authority.clone()))
+    }
+}
+
+//TLS of the connection, or X-Forwarded-Proto when the peer is a trusted proxy. Without connection info, like for
+//requests built by hand, the URI's scheme if it has one, and http otherwise.
+fn own_scheme(request_parts: &Parts) -> String {
+    let info = match request_parts.extensions.get::<ConnectionInfo>() {
+        Some(info) => info,
+        None => return request_parts.uri.scheme_str().unwrap_or("http").to_ascii_lowercase(),
+    };
+    let forwarded_proto = request_parts
+        .headers
+        .get(HEADER_X_FORWARDED_PROTO)
+        .and_then(|proto| proto.to_str().ok())
+        //The first entry was added by the proxy nearest to the client.
+        .and_then(|proto| proto.split(',').next())
+        .map(|proto| proto.trim().to_ascii_lowercase());
+    match (info.peer_is_trusted_proxy, forwarded_proto) {
+        (true, Some(proto)) => proto,
+        _ if info.tls => "https".to_string(),
+        _ => "http".to_string(),
+    }
+}
+
+//Lowercase scheme and authority, without the scheme's default port.
+fn split_origin(origin: &str) -> Option<(String, String)> {
+    let uri = origin.parse::<Uri>().ok()?;
+    let scheme = uri.scheme_str()?.to_ascii_lowercase();
+    let authority = without_default_port(&scheme, uri.authority()?.as_str());
+    Some((scheme, authority))
+}
+
+fn without_default_port(scheme: &str, authority: &str) -> String {
+    let authority = authority.to_ascii_lowercase();
+    let default_port = match scheme {
+        "http" => ":80",
+        "https" => ":443",
+        _ => return authority,
+    };
+    match authority.strip_suffix(default_port) {
+        Some(host) => host.to_string(),
+        None => authority,
+    }
+}
+
+fn is_safe_method(method: &Method) -> bool {
+    matches!(*method, Method::GET | Method::HEAD | Method::OPTIONS | Method::TRACE)
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    a.iter().zip(b).fold(0u8, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+fn escape_html(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+//Reads a field of an application/x-www-form-urlencoded body.
+pub fn form_value(body: &[u8], field: &str) -> Option<String> {
+    let body = std::str::from_utf8(body).ok()?;
+    body.split('&')
+        .filter_map(|pair| pair.split_once('=').or(Some((pair, ""))))
+        .find(|(name, _)| percent_decode(name).as_deref() == Some(field))
+        .and_then(|(_, value)| percent_decode(value))
+}
+
+fn percent_decode(text: &str) -> Option<String> {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut index = 0;
+    while index < bytes.len() {
+        match bytes[index] {
+            b'+' => decoded.push(b' '),
+            b'%' => {
+                let hex = text.get(index + 1..index + 3)?;
+                decoded.push(u8::from_str_radix(hex, 16).ok()?);
+                index += 2;
+            }
+            byte => decoded.push(byte),
+        }
+        index += 1;
+    }
+    String::from_utf8(decoded).ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::Request;
+
+    fn parts(method: Method, headers: &[(&str, &str)]) -> Parts {
+        let mut builder = Request::builder().method(method).uri("/transfer");
+        for (name, value) in headers {
+            builder = builder.header(*name, *value);
+        }
+        builder.body(()).unwrap().into_parts().0
+    }
+
+    fn over(mut parts: Parts, tls: bool, peer_is_trusted_proxy: bool) -> Parts {
+        parts.extensions.insert(ConnectionInfo {
+            peer_addr: Some("192.0.2.1:40000".parse().unwrap()),
+            local_addr: None,
+            tls,
+            unix_peer: None,
+            peer_is_trusted_proxy,
+        });
+        parts
+    }
+
+    fn passes(handler: Handler) -> bool {
+        match handler {
+            Handler::Continue => true,
+            Handler::ImmediateReturn(response) => {
+                assert_eq!(response.status(), StatusCode::FORBIDDEN);
+                false
+            }
+            Handler::Error(e) => panic!("{}", e),
+        }
+    }
+
+    #[test]
+    fn double_submit_compares_cookie_with_header_or_form() {
+        let csrf = CsrfProtection::new();
+        let cookie = "session=abc; csrf_token=t0k3n";
+        assert!(passes(csrf.check_double_submit(&parts(Method::GET, &[]), None)));
+        assert!(passes(csrf.check_double_submit(
+            &over(parts(Method::POST, &[("cookie", cookie), ("x-csrf-token", "t0k3n"), ("host", "example.com"), ("origin", "https://example.com")]), true, false),
+            None
+        )));
+        assert!(passes(csrf.check_double_submit(&parts(Method::POST, &[("cookie", cookie)]), Some(b"amount=10&csrf_token=t0k3n"))));
+        assert!(!passes(csrf.check_double_submit(&parts(Method::POST, &[("cookie", cookie), ("x-csrf-token", "other")]), None)));
+        assert!(!passes(csrf.check_double_submit(&parts(Method::POST, &[("x-csrf-token", "")]), None)));
+    }
+
+    #[test]
+    fn checks_origin_of_unsafe_requests() {
+        let csrf = CsrfProtection::new().with_trusted_origin("https://app.example.com/");
+        let check = |headers: &[(&str, &str)]| {
+            let mut all = vec![("host", "api.example.com"), ("x-csrf-token", "s")];
+            all.extend_from_slice(headers);
+            passes(csrf.check_synchronizer(&over(parts(Method::DELETE, &all), true, false), "s", None))
+        };
+        assert!(check(&[("origin", "https://api.example.com")]));
+        assert!(check(&[("origin", "https://APP.example.com")]));
+        assert!(check(&[("referer", "https://api.example.com/form?x=1")]));
+        assert!(check(&[]));
+        assert!(!check(&[("origin", "https://evil.example")]));
+        assert!(!check(&[("origin", "null")]));
+    }
+
+    #[test]
+    fn ignores_default_ports_and_falls_back_to_the_uri() {
+        let csrf = CsrfProtection::new().with_trusted_origin("https://app.example.com:443");
+        let check = |uri: &str, tls: Option<bool>, headers: &[(&str, &str)]| {
+            let mut all = vec![("x-csrf-token", "s")];
+            all.extend_from_slice(headers);
+            let mut request = parts(Method::POST, &all);
+            request.uri = uri.parse().unwrap();
+            if let Some(tls) = tls {
+                request = over(request, tls, false);
+            }
+            passes(csrf.check_synchronizer(&request, "s", None))
+        };
+        assert!(check("/", Some(true), &[("host", "api.example.com:443"), ("origin", "https://api.example.com")]));
+        assert!(check("/", Some(false), &[("host", "api.example.com"), ("origin", "http://api.example.com:80")]));
+        assert!(check("/", Some(false), &[("host", "api.example.com"), ("origin", "https://app.example.com")]));
+        assert!(!check("/", Some(true), &[("host", "api.example.com:8443"), ("origin", "https://api.example.com")]));
+        assert!(!check("/", Some(false), &[("host", "api.example.com:443"), ("origin", "http://api.example.com")]));
+        assert!(check("https://api.example.com/transfer", None, &[("origin", "https://api.example.com")]));
+        assert!(!check("https://api.example.com/transfer", None, &[("origin", "https://evil.example")]));
+        assert!(!check("http://api.example.com/transfer", None, &[("origin", "https://api.example.com")]));
+    }
+
+    #[test]
+    fn compares_the_scheme_of_the_own_origin() {
+        let csrf = CsrfProtection::new();
+        let check = |tls: bool, peer_is_trusted_proxy: bool, headers: &[(&str, &str)]| {
+            let mut all = vec![("x-csrf-token", "s"), ("host", "api.example.com")];
+            all.extend_from_slice(headers);
+            passes(csrf.check_synchronizer(&over(parts(Method::POST, &all), tls, peer_is_trusted_proxy), "s", None))
+        };
+        assert!(!check(true, false, &[("origin", "http://api.example.com")]));
+        assert!(!check(false, false, &[("origin", "https://api.example.com")]));
+        assert!(!check(false, false, &[("origin", "https://api.example.com"), ("x-forwarded-proto", "https")]));
+        assert!(check(false, true, &[("origin", "https://api.example.com"), ("x-forwarded-proto", "https")]));
+        assert!(!check(true, true, &[("origin", "http://api.example.com"), ("x-forwarded-proto", "https")]));
+    }
+
+    #[test]
+    fn embeds_token_in_forms_and_cookies() {
+        let csrf = CsrfProtection::new();
+        let token = csrf.token_for(&HeaderMap::new());
+        assert!(csrf.hidden_input(&token).contains(&format!("value=\"{}\"", token.as_str())));
+        let response = csrf.set_token_cookie(Response::new(()), &token);
+        assert_eq!(
+            response.headers()[header::SET_COOKIE],
+            format!("csrf_token={}; Path=/; SameSite=Strict; Secure", token.as_str()).as_str()
+        );
+        assert_eq!(form_value(b"a=1&csrf_token=x%2By+z", "csrf_token").as_deref(), Some("x+y z"));
+    }
+}
@@ -1,4 +1,4 @@
-use hyper::Response;
+use hyper::{Response, StatusCode};
 use serde::Serialize;
 
 use crate::{commons::HandlerBody, response_building::bytes_to_boxed_body};
@@ -13,18 +13,28 @@ struct Error {
     error: ErrorContents,
 }
 
+//Keeps the 200 status existing callers rely on. Prefer generic_json_error_with_status.
 pub fn generic_json_error(message: &str) -> Response<HandlerBody> {
+    generic_json_error_with_status(StatusCode::OK, message)
+}
+
+pub fn generic_json_error_with_status(status: StatusCode, message: &str) -> Response<HandlerBody> {
     let e = Error {
         error: ErrorContents {
             message: message.to_string(),
         },
     };
 
-    Response::new(bytes_to_boxed_body(
-        serde_json::to_string(&e).expect("Couldn't serialize error message."),
-    ))
+    Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .body(bytes_to_boxed_body(
+            serde_json::to_string(&e).expect("Couldn't serialize error message."),
+        ))
+        .expect("Should produce response.")
 }
 
+#[deprecated(note = "Exposes Debug output to clients. Use problem::Problem instead.")]
 pub fn generic_json_error_from_debug<T>(e: T) -> Response<HandlerBody>
 where
     T: std::fmt::Debug,
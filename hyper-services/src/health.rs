@@ -0,0 +1,259 @@
+use std::{future::Future, sync::Arc, time::Duration};
+
+use futures_util::future::{join_all, BoxFuture};
+use hyper::{body::Incoming, Method, Request, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{
+    commons::{HandlerBody, HandlerError, HandlerResult},
+    response_building::bytes_to_boxed_body,
+    service::stateful_service::StatefulHandler,
+};
+
+pub const DEFAULT_LIVENESS_PATH: &str = "/healthz";
+pub const DEFAULT_READINESS_PATH: &str = "/readyz";
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    //Failing means the process should be restarted.
+    Liveness,
+    //Failing means the process shouldn't receive traffic yet.
+    Readiness,
+}
+
+type CheckFunction = dyn Fn() -> BoxFuture<'static, Result<(), HandlerError>> + Send + Sync;
+
+struct HealthCheck {
+    name: String,
+    probe: Probe,
+    timeout: Duration,
+    check: Box<CheckFunction>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Pass,
+    Fail,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct CheckReport {
+    pub name: String,
+    pub status: HealthStatus,
+    pub duration_ms: f64,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthReport {
+    pub status: HealthStatus,
+    pub checks: Vec<CheckReport>,
+}
+
+//Named async checks, registered at startup and run on every probe request.
+#[derive(Clone)]
+pub struct HealthChecks {
+    checks: Vec<Arc<HealthCheck>>,
+    liveness_path: String,
+    readiness_path: String,
+}
+
+impl Default for HealthChecks {
+    fn default() -> Self {
+        HealthChecks {
+            checks: Vec::new(),
+            liveness_path: DEFAULT_LIVENESS_PATH.to_string(),
+            readiness_path: DEFAULT_READINESS_PATH.to_string(),
+        }
+    }
+}
+
+impl HealthChecks {
+    pub fn new() -> HealthChecks {
+        HealthChecks::default()
+    }
+
+    pub fn with_paths(mut self, liveness_path: &str, readiness_path: &str) -> HealthChecks {
+        self.liveness_path = liveness_path.to_string();
+        self.readiness_path = readiness_path.to_string();
+        self
+    }
+
+    pub fn liveness<F, Fut>(self, name: &str, check: F) -> HealthChecks
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        self.register(name, Probe::Liveness, DEFAULT_TIMEOUT, check)
+    }
+
+    pub fn readiness<F, Fut>(self, name: &str, check: F) -> HealthChecks
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        self.register(name, Probe::Readiness, DEFAULT_TIMEOUT, check)
+    }
+
+    pub fn register<F, Fut>(
+        mut self,
+        name: &str,
+        probe: Probe,
+        timeout: Duration,
+        check: F,
+    ) -> HealthChecks
+    where
+        F: Fn() -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        self.checks.push(Arc::new(HealthCheck {
+            name: name.to_string(),
+            probe,
+            timeout,
+            check: Box::new(move || Box::pin(check())),
+        }));
+        self
+    }
+
+    //Readiness includes the liveness checks, since a process that isn't alive can't be ready either.
+    pub async fn run(&self, probe: Probe) -> HealthReport {
+        let checks = self
+            .checks
+            .iter()
+            .filter(|check| probe == Probe::Readiness || check.probe == Probe::Liveness)
+            .map(|check| async move {
+                let started = std::time::Instant::now();
+                let outcome = tokio::time::timeout(check.timeout, (check.check)()).await;
+                let duration_ms = started.elapsed().as_secs_f64() * 1000.0;
+                let error = match outcome {
+                    Ok(Ok(())) => None,
+                    Ok(Err(e)) => Some(e.to_string()),
+                    Err(_) => Some(format!("Timed out after {:?}.", check.timeout)),
+                };
+                if let Some(error) = &error {
+                    tracing::warn!(check = check.name, error, "Health check failed.");
+                }
+                CheckReport {
+                    name: check.name.clone(),
+                    status: match error {
+                        Some(_) => HealthStatus::Fail,
+                        None => HealthStatus::Pass,
+                    },
+                    duration_ms,
+                    error,
+                }
+            });
+
+        let checks = join_all(checks).await;
+        let status = match checks
+            .iter()
+            .all(|check| check.status == HealthStatus::Pass)
+        {
+            true => HealthStatus::Pass,
+            false => HealthStatus::Fail,
+        };
+        HealthReport { status, checks }
+    }
+
+    pub async fn response(&self, probe: Probe) -> Response<HandlerBody> {
+        let report = self.run(probe).await;
+        let status = match report.status {
+            HealthStatus::Pass => StatusCode::OK,
+            HealthStatus::Fail => StatusCode::SERVICE_UNAVAILABLE,
+        };
+        Response::builder()
+            .status(status)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(bytes_to_boxed_body(
+                serde_json::to_string(&report).expect("Should serialize."),
+            ))
+            .expect("Should produce response.")
+    }
+
+    //Returns the probe a request is aimed at, if any.
+    pub fn probe_for<T>(&self, request: &Request<T>) -> Option<Probe> {
+        if request.method() != Method::GET && request.method() != Method::HEAD {
+            return None;
+        }
+        let path = request.uri().path();
+        if path == self.liveness_path {
+            Some(Probe::Liveness)
+        } else if path == self.readiness_path {
+            Some(Probe::Readiness)
+        } else {
+            None
+        }
+    }
+
+    //Serves the probe endpoints in front of another handler.
+    pub fn mount<T: StatefulHandler>(self, handler: T) -> WithHealth<T> {
+        WithHealth {
+            health: self,
+            handler,
+        }
+    }
+}
+
+#[derive(Clone)]
+pub struct WithHealth<T: StatefulHandler> {
+    health: HealthChecks,
+    handler: T,
+}
+
+impl<T> StatefulHandler for WithHealth<T>
+where
+    T: StatefulHandler,
+{
+    async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+        match self.health.probe_for(&request) {
+            Some(probe) => Ok(self.health.response(probe).await),
+            None => self.handler.handle_request(request).await,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn aggregates_check_results() {
+        let health = HealthChecks::new()
+            .liveness("process", || async { Ok(()) })
+            .readiness("database", || async { Err("connection refused".into()) })
+            .register(
+                "slow",
+                Probe::Readiness,
+                Duration::from_millis(10),
+                || async {
+                    tokio::time::sleep(Duration::from_secs(1)).await;
+                    Ok(())
+                },
+            );
+
+        let liveness = health.run(Probe::Liveness).await;
+        assert_eq!(liveness.status, HealthStatus::Pass);
+        assert_eq!(liveness.checks.len(), 1);
+
+        let readiness = health.run(Probe::Readiness).await;
+        assert_eq!(readiness.status, HealthStatus::Fail);
+        assert_eq!(readiness.checks.len(), 3);
+        assert_eq!(
+            readiness.checks[1].error.as_deref(),
+            Some("connection refused")
+        );
+        assert!(readiness.checks[2]
+            .error
+            .as_deref()
+            .unwrap()
+            .starts_with("Timed out"));
+
+        let response = health.response(Probe::Readiness).await;
+        assert_eq!(response.status(), StatusCode::SERVICE_UNAVAILABLE);
+    }
+}
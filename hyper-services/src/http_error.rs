@@ -0,0 +1,203 @@
+use hyper::StatusCode;
+
+use crate::{
+    commons::HandlerError,
+    problem::{IntoProblem, Problem},
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HttpErrorKind {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    Conflict,
+    PayloadTooLarge,
+    RequestTimeout,
+    Internal,
+}
+
+impl HttpErrorKind {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpErrorKind::BadRequest => StatusCode::BAD_REQUEST,
+            HttpErrorKind::Unauthorized => StatusCode::UNAUTHORIZED,
+            HttpErrorKind::Forbidden => StatusCode::FORBIDDEN,
+            HttpErrorKind::NotFound => StatusCode::NOT_FOUND,
+            HttpErrorKind::Conflict => StatusCode::CONFLICT,
+            HttpErrorKind::PayloadTooLarge => StatusCode::PAYLOAD_TOO_LARGE,
+            HttpErrorKind::RequestTimeout => StatusCode::REQUEST_TIMEOUT,
+            HttpErrorKind::Internal => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+//Returned from handlers (boxed into a HandlerError) to choose the response the service sends.
+#[derive(Debug)]
+pub struct HttpError {
+    kind: HttpErrorKind,
+    message: String,
+    source: Option<HandlerError>,
+}
+
+impl HttpError {
+    pub fn new(kind: HttpErrorKind, message: &str) -> HttpError {
+        HttpError {
+            kind,
+            message: message.to_string(),
+            source: None,
+        }
+    }
+
+    pub fn bad_request(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::BadRequest, message)
+    }
+
+    pub fn unauthorized(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::Forbidden, message)
+    }
+
+    pub fn not_found(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::NotFound, message)
+    }
+
+    pub fn conflict(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::Conflict, message)
+    }
+
+    pub fn payload_too_large(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::PayloadTooLarge, message)
+    }
+
+    pub fn request_timeout(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::RequestTimeout, message)
+    }
+
+    pub fn internal(message: &str) -> HttpError {
+        HttpError::new(HttpErrorKind::Internal, message)
+    }
+
+    pub fn with_source<E: Into<HandlerError>>(mut self, source: E) -> HttpError {
+        self.source = Some(source.into());
+        self
+    }
+
+    pub fn kind(&self) -> HttpErrorKind {
+        self.kind
+    }
+
+    pub fn status(&self) -> StatusCode {
+        self.kind.status()
+    }
+
+    pub fn message(&self) -> &str {
+        &self.message
+    }
+}
+
+impl std::fmt::Display for HttpError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}: {}", self.status(), self.message)
+    }
+}
+
+impl std::error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match &self.source {
+            Some(source) => Some(source.as_ref()),
+            None => None,
+        }
+    }
+}
+
+//Client errors carry their message as the detail. Internal messages stay in the logs.
+impl IntoProblem for &HttpError {
+    fn into_problem(self) -> Problem {
+        let problem = Problem::new(self.status());
+        match self.kind {
+            HttpErrorKind::Internal => problem,
+            _ => problem.with_detail(&self.message),
+        }
+    }
+}
+
+impl IntoProblem for HttpError {
+    fn into_problem(self) -> Problem {
+        (&self).into_problem()
+    }
+}
+
+//Problem for any error returned by a handler. Errors that aren't HttpErrors are treated as internal.
+pub fn problem_for_handler_error(error: &HandlerError) -> Problem {
+    match error.downcast_ref::<HttpError>() {
+        Some(http_error) => http_error.into_problem(),
+        None => Problem::new(StatusCode::INTERNAL_SERVER_ERROR),
+    }
+}
+
+//Renders the error with its whole source chain, for logging.
+pub fn error_chain(error: &(dyn std::error::Error + 'static)) -> String {
+    let mut chain = error.to_string();
+    let mut source = error.source();
+    while let Some(cause) = source {
+        chain.push_str(": ");
+        chain.push_str(&cause.to_string());
+        source = cause.source();
+    }
+    chain
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::BodyExt;
+    use serde_json::Value;
+
+    use super::*;
+    use crate::problem::CONTENT_TYPE_PROBLEM_JSON;
+
+    async fn problem_json(error: HttpError) -> (StatusCode, Value) {
+        let response = error.into_problem_response();
+        assert_eq!(response.headers()[hyper::header::CONTENT_TYPE], CONTENT_TYPE_PROBLEM_JSON);
+        let status = response.status();
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    #[tokio::test]
+    async fn maps_kinds_to_problem_responses() {
+        let cases = [
+            (HttpError::bad_request("Missing name."), StatusCode::BAD_REQUEST),
+            (HttpError::unauthorized("Log in first."), StatusCode::UNAUTHORIZED),
+            (HttpError::forbidden("Not yours."), StatusCode::FORBIDDEN),
+            (HttpError::not_found("No such item."), StatusCode::NOT_FOUND),
+            (HttpError::conflict("Already exists."), StatusCode::CONFLICT),
+            (HttpError::payload_too_large("Too big."), StatusCode::PAYLOAD_TOO_LARGE),
+            (HttpError::request_timeout("Too slow."), StatusCode::REQUEST_TIMEOUT),
+        ];
+        for (error, status) in cases {
+            let message = error.message().to_string();
+            let (response_status, problem) = problem_json(error).await;
+            assert_eq!(response_status, status);
+            assert_eq!(problem["status"], status.as_u16());
+            assert_eq!(problem["title"], status.canonical_reason().unwrap());
+            assert_eq!(problem["detail"], message.as_str());
+        }
+    }
+
+    #[tokio::test]
+    async fn keeps_internal_details_out_of_responses() {
+        let error = HttpError::internal("Database password rejected.").with_source(std::io::Error::other("refused"));
+        assert_eq!(error_chain(&error), "500 Internal Server Error: Database password rejected.: refused");
+        let (status, problem) = problem_json(error).await;
+        assert_eq!(status, StatusCode::INTERNAL_SERVER_ERROR);
+        assert!(problem.get("detail").is_none());
+
+        let other: HandlerError = "Connection reset.".into();
+        assert_eq!(problem_for_handler_error(&other).status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(problem_for_handler_error(&other).detail(), None);
+    }
+}
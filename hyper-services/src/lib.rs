@@ -1,6 +1,20 @@
+pub mod access_control;
+pub mod client;
 pub mod commons;
 pub mod cors;
+pub mod csrf;
 pub mod generic_json_error;
+pub mod health;
+pub mod http_error;
+#[cfg(feature = "openapi")]
+pub mod openapi;
+pub mod problem;
+pub mod proxy;
+pub mod request_id;
 pub mod request_processing;
 pub mod response_building;
-pub mod service;
\ No newline at end of file
+pub mod service;
+#[cfg(any(test, feature = "test-util"))]
+pub mod testing;
+pub mod websocket;
+pub mod vhost;
\ No newline at end of file
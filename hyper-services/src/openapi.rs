@@ -0,0 +1,413 @@
+use hyper::{body::Bytes, body::Incoming, header, Method, Request, Response, StatusCode};
+use schemars::{generate::SchemaSettings, JsonSchema, Schema, SchemaGenerator};
+use serde_json::{json, Map, Value};
+
+use crate::{
+    commons::HandlerResult,
+    response_building::{bytes_to_boxed_body, empty_body, not_found, send_file},
+    service::stateful_service::StatefulHandler,
+};
+
+//Derive macros refer to the schemars crate, so it's re-exported for callers that don't depend on it directly.
+pub use schemars;
+
+const OPENAPI_VERSION: &str = "3.1.0";
+const JSON_CONTENT_TYPE: &str = "application/json";
+#[cfg(feature = "swagger-ui")]
+const SPEC_URL_PLACEHOLDER: &str = "{{spec_url}}";
+//Swagger UI 5.17.14 is bundled from its release (assets/swagger-ui holds its license), so the page works offline
+//and without a CDN. There's no inline script, so it also works under a script-src 'self' policy.
+#[cfg(feature = "swagger-ui")]
+const SWAGGER_UI_PAGE: &str = r##"<!DOCTYPE html>
+<html lang="en">
+<head>
+  <meta charset="utf-8">
+  <title>API documentation</title>
+  <link rel="stylesheet" href="swagger-ui.css">
+</head>
+<body>
+  <div id="swagger-ui"></div>
+  <script src="swagger-ui-bundle.js"></script>
+  <script src="swagger-initializer.js"></script>
+</body>
+</html>
+"##;
+#[cfg(feature = "swagger-ui")]
+const SWAGGER_UI_INITIALIZER: &str = r##"window.onload = () => { window.ui = SwaggerUIBundle({ url: {{spec_url}}, dom_id: "#swagger-ui" }); };
+"##;
+#[cfg(feature = "swagger-ui")]
+const SWAGGER_UI_FILES: [(&str, &[u8]); 2] = [
+    ("swagger-ui-bundle.js", include_bytes!("../assets/swagger-ui/swagger-ui-bundle.js")),
+    ("swagger-ui.css", include_bytes!("../assets/swagger-ui/swagger-ui.css")),
+];
+
+type SchemaFn = fn(&mut SchemaGenerator) -> Schema;
+
+fn schema_for<T: JsonSchema>(generator: &mut SchemaGenerator) -> Schema {
+    generator.subschema_for::<T>()
+}
+
+#[derive(Debug, Clone)]
+struct ApiResponse {
+    status: StatusCode,
+    description: String,
+    schema: Option<SchemaFn>,
+}
+
+//Describes one operation. Schemas come from types deriving schemars::JsonSchema.
+#[derive(Debug, Clone)]
+pub struct ApiRoute {
+    method: Method,
+    //A path template like /items/{id}. Its parameters are documented as strings.
+    path: String,
+    summary: Option<String>,
+    description: Option<String>,
+    tags: Vec<String>,
+    operation_id: Option<String>,
+    request: Option<SchemaFn>,
+    responses: Vec<ApiResponse>,
+}
+
+impl ApiRoute {
+    pub fn new(method: Method, path: &str) -> ApiRoute {
+        ApiRoute {
+            method,
+            path: path.to_string(),
+            summary: None,
+            description: None,
+            tags: Vec::new(),
+            operation_id: None,
+            request: None,
+            responses: Vec::new(),
+        }
+    }
+
+    pub fn get(path: &str) -> ApiRoute {
+        ApiRoute::new(Method::GET, path)
+    }
+
+    pub fn post(path: &str) -> ApiRoute {
+        ApiRoute::new(Method::POST, path)
+    }
+
+    pub fn put(path: &str) -> ApiRoute {
+        ApiRoute::new(Method::PUT, path)
+    }
+
+    pub fn patch(path: &str) -> ApiRoute {
+        ApiRoute::new(Method::PATCH, path)
+    }
+
+    pub fn delete(path: &str) -> ApiRoute {
+        ApiRoute::new(Method::DELETE, path)
+    }
+
+    pub fn summary(mut self, summary: &str) -> ApiRoute {
+        self.summary = Some(summary.to_string());
+        self
+    }
+
+    pub fn description(mut self, description: &str) -> ApiRoute {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn tag(mut self, tag: &str) -> ApiRoute {
+        self.tags.push(tag.to_string());
+        self
+    }
+
+    pub fn operation_id(mut self, operation_id: &str) -> ApiRoute {
+        self.operation_id = Some(operation_id.to_string());
+        self
+    }
+
+    //A required JSON request body.
+    pub fn request<T: JsonSchema>(mut self) -> ApiRoute {
+        self.request = Some(schema_for::<T>);
+        self
+    }
+
+    //A JSON response body.
+    pub fn response<T: JsonSchema>(mut self, status: StatusCode, description: &str) -> ApiRoute {
+        self.responses.push(ApiResponse {
+            status,
+            description: description.to_string(),
+            schema: Some(schema_for::<T>),
+        });
+        self
+    }
+
+    pub fn empty_response(mut self, status: StatusCode, description: &str) -> ApiRoute {
+        self.responses.push(ApiResponse {
+            status,
+            description: description.to_string(),
+            schema: None,
+        });
+        self
+    }
+
+    fn operation(&self, generator: &mut SchemaGenerator) -> Value {
+        let mut operation = Map::new();
+        if let Some(summary) = &self.summary {
+            operation.insert("summary".to_string(), json!(summary));
+        }
+        if let Some(description) = &self.description {
+            operation.insert("description".to_string(), json!(description));
+        }
+        if !self.tags.is_empty() {
+            operation.insert("tags".to_string(), json!(self.tags));
+        }
+        if let Some(operation_id) = &self.operation_id {
+            operation.insert("operationId".to_string(), json!(operation_id));
+        }
+
+        let parameters: Vec<Value> = path_parameters(&self.path)
+            .map(|name| json!({ "name": name, "in": "path", "required": true, "schema": { "type": "string" } }))
+            .collect();
+        if !parameters.is_empty() {
+            operation.insert("parameters".to_string(), json!(parameters));
+        }
+
+        if let Some(request) = self.request {
+            operation.insert(
+                "requestBody".to_string(),
+                json!({ "required": true, "content": { JSON_CONTENT_TYPE: { "schema": request(generator) } } }),
+            );
+        }
+
+        let mut responses = Map::new();
+        for response in &self.responses {
+            let mut entry = json!({ "description": response.description });
+            if let Some(schema) = response.schema {
+                entry["content"] = json!({ JSON_CONTENT_TYPE: { "schema": schema(generator) } });
+            }
+            responses.insert(response.status.as_u16().to_string(), entry);
+        }
+        //OpenAPI requires at least one response.
+        if responses.is_empty() {
+            responses.insert("default".to_string(), json!({ "description": "Response." }));
+        }
+        operation.insert("responses".to_string(), Value::Object(responses));
+        Value::Object(operation)
+    }
+}
+
+fn path_parameters(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{').and_then(|segment| segment.strip_suffix('}')))
+}
+
+//Collects route descriptions into an OpenAPI 3.1 document. It only documents routes, handlers still dispatch them.
+#[derive(Debug, Clone)]
+pub struct OpenApi {
+    title: String,
+    version: String,
+    description: Option<String>,
+    routes: Vec<ApiRoute>,
+}
+
+impl OpenApi {
+    pub fn new(title: &str, version: &str) -> OpenApi {
+        OpenApi {
+            title: title.to_string(),
+            version: version.to_string(),
+            description: None,
+            routes: Vec::new(),
+        }
+    }
+
+    pub fn with_description(mut self, description: &str) -> OpenApi {
+        self.description = Some(description.to_string());
+        self
+    }
+
+    pub fn route(mut self, route: ApiRoute) -> OpenApi {
+        self.routes.push(route);
+        self
+    }
+
+    //Named types end up once in components/schemas and are referenced from the operations.
+    pub fn document(&self) -> Value {
+        let mut generator = SchemaSettings::draft2020_12()
+            .with(|settings| settings.definitions_path = "/components/schemas".into())
+            .into_generator();
+
+        let mut paths = Map::new();
+        for route in &self.routes {
+            let operation = route.operation(&mut generator);
+            let item = paths.entry(route.path.clone()).or_insert_with(|| json!({}));
+            item[route.method.as_str().to_ascii_lowercase()] = operation;
+        }
+
+        let mut info = json!({ "title": self.title, "version": self.version });
+        if let Some(description) = &self.description {
+            info["description"] = json!(description);
+        }
+        json!({
+            "openapi": OPENAPI_VERSION,
+            "info": info,
+            "paths": paths,
+            "components": { "schemas": generator.take_definitions(true) },
+        })
+    }
+}
+
+//Writes the documentation page and the bundled Swagger UI into a directory, for OpenApiDocs::with_ui.
+//spec_url is where browsers fetch the document, e.g. "/openapi.json".
+#[cfg(feature = "swagger-ui")]
+pub async fn install_swagger_ui(directory: &std::path::Path, spec_url: &str) -> std::io::Result<()> {
+    tokio::fs::create_dir_all(directory).await?;
+    tokio::fs::write(directory.join("index.html"), SWAGGER_UI_PAGE).await?;
+    let initializer = SWAGGER_UI_INITIALIZER.replace(SPEC_URL_PLACEHOLDER, &serde_json::to_string(spec_url)?);
+    tokio::fs::write(directory.join("swagger-initializer.js"), initializer).await?;
+    for (name, contents) in SWAGGER_UI_FILES {
+        tokio::fs::write(directory.join(name), contents).await?;
+    }
+    Ok(())
+}
+
+//Serves the document, and optionally a documentation page through send_file.
+#[derive(Debug, Clone)]
+pub struct OpenApiDocs {
+    document: Bytes,
+    spec_path: String,
+    //The path prefix and directory of the documentation page.
+    ui: Option<(String, String)>,
+}
+
+impl OpenApiDocs {
+    pub fn new(api: &OpenApi, spec_path: &str) -> OpenApiDocs {
+        OpenApiDocs {
+            document: Bytes::from(api.document().to_string()),
+            spec_path: spec_path.to_string(),
+            ui: None,
+        }
+    }
+
+    //Serves files from directory under path, see install_swagger_ui.
+    pub fn with_ui(mut self, path: &str, directory: &str) -> OpenApiDocs {
+        self.ui = Some((path.trim_end_matches('/').to_string(), directory.trim_end_matches('/').to_string()));
+        self
+    }
+
+    //For handlers that dispatch by hand: whether handle_request should answer this path.
+    pub fn handles(&self, path: &str) -> bool {
+        path == self.spec_path || self.ui_file(path).is_some()
+    }
+
+    //The bare prefix maps to "", which is redirected so the page's relative links resolve under the prefix.
+    fn ui_file<'a>(&self, path: &'a str) -> Option<&'a str> {
+        let (prefix, _) = self.ui.as_ref()?;
+        match path.strip_prefix(prefix.as_str())? {
+            rest if rest.is_empty() || rest.starts_with('/') => Some(rest),
+            _ => None,
+        }
+    }
+}
+
+impl StatefulHandler for OpenApiDocs {
+    async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+        let path = request.uri().path();
+        if path == self.spec_path {
+            return Ok(Response::builder()
+                .header(header::CONTENT_TYPE, JSON_CONTENT_TYPE)
+                .body(bytes_to_boxed_body(self.document.clone()))?);
+        }
+        match (self.ui_file(path), &self.ui) {
+            (Some(""), _) => Ok(Response::builder()
+                .status(StatusCode::PERMANENT_REDIRECT)
+                .header(header::LOCATION, format!("{}/", path))
+                .body(empty_body())?),
+            (Some(file), Some((_, directory))) => send_file(directory, file, None).await,
+            _ => Ok(not_found()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::{Deserialize, Serialize};
+
+    use super::*;
+    use crate::{service::stateful_service::StatefulService, testing::TestClient};
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct Item {
+        id: u64,
+        name: String,
+    }
+
+    #[derive(Serialize, Deserialize, JsonSchema)]
+    struct NewItem {
+        name: String,
+    }
+
+    fn api() -> OpenApi {
+        OpenApi::new("Items", "1.0")
+            .route(ApiRoute::get("/items/{id}").summary("Get an item").tag("items").response::<Item>(StatusCode::OK, "The item"))
+            .route(
+                ApiRoute::post("/items")
+                    .tag("items")
+                    .request::<NewItem>()
+                    .response::<Item>(StatusCode::CREATED, "The created item"),
+            )
+            .route(ApiRoute::delete("/items/{id}").empty_response(StatusCode::NO_CONTENT, "Deleted"))
+    }
+
+    #[test]
+    fn documents_routes_and_schemas() {
+        let document = api().document();
+        assert_eq!(document["openapi"], "3.1.0");
+        let get = &document["paths"]["/items/{id}"]["get"];
+        assert_eq!(get["summary"], "Get an item");
+        assert_eq!(get["parameters"][0]["name"], "id");
+        assert_eq!(get["responses"]["200"]["content"]["application/json"]["schema"]["$ref"], "#/components/schemas/Item");
+        assert!(document["paths"]["/items/{id}"]["delete"]["responses"]["204"]["content"].is_null());
+        assert_eq!(
+            document["paths"]["/items"]["post"]["requestBody"]["content"]["application/json"]["schema"]["$ref"],
+            "#/components/schemas/NewItem"
+        );
+        assert_eq!(document["components"]["schemas"]["Item"]["properties"]["name"]["type"], "string");
+    }
+
+    #[cfg(feature = "swagger-ui")]
+    #[tokio::test]
+    async fn serves_document_and_page() {
+        let directory = std::env::temp_dir().join(format!("hyper-services-openapi-{}", std::process::id()));
+        install_swagger_ui(&directory, "/openapi.json").await.unwrap();
+        let docs = OpenApiDocs::new(&api(), "/openapi.json").with_ui("/docs", directory.to_str().unwrap());
+        assert!(docs.handles("/docs/") && !docs.handles("/docsx"));
+
+        let client = TestClient::new(StatefulService::create(docs));
+        let response = client.get("/openapi.json").send().await;
+        response.assert_status(StatusCode::OK).assert_header("content-type", JSON_CONTENT_TYPE);
+        assert_eq!(response.json::<Value>()["info"]["title"], "Items");
+        client
+            .get("/docs")
+            .send()
+            .await
+            .assert_status(StatusCode::PERMANENT_REDIRECT)
+            .assert_header("location", "/docs/");
+        let page = client.get("/docs/").send().await;
+        page.assert_status(StatusCode::OK).assert_header("content-type", "text/html");
+        assert!(!page.text().contains("<script>") && !page.text().contains("https://"));
+        let initializer = client.get("/docs/swagger-initializer.js").send().await;
+        initializer.assert_status(StatusCode::OK).assert_header("content-type", "text/javascript");
+        assert!(initializer.text().contains("url: \"/openapi.json\""));
+        let bundle = client.get("/docs/swagger-ui-bundle.js").send().await;
+        bundle.assert_status(StatusCode::OK);
+        assert_eq!(bundle.body.len(), SWAGGER_UI_FILES[0].1.len());
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+
+    #[cfg(feature = "swagger-ui")]
+    #[tokio::test]
+    async fn escapes_the_spec_url() {
+        let directory = std::env::temp_dir().join(format!("hyper-services-openapi-escape-{}", std::process::id()));
+        install_swagger_ui(&directory, "/open\"api\\.json").await.unwrap();
+        let initializer = std::fs::read_to_string(directory.join("swagger-initializer.js")).unwrap();
+        assert!(initializer.contains(r#"url: "/open\"api\\.json","#), "{}", initializer);
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,176 @@
+use hyper::{Response, StatusCode};
+use serde::Serialize;
+use serde_json::{Map, Value};
+
+use crate::{
+    commons::{HandlerBody, HandlerResult},
+    response_building::bytes_to_boxed_body,
+};
+
+pub const CONTENT_TYPE_PROBLEM_JSON: &str = "application/problem+json";
+
+const DEFAULT_TYPE: &str = "about:blank";
+const RESERVED_MEMBERS: [&str; 5] = ["type", "title", "status", "detail", "instance"];
+
+//RFC 7807 problem details.
+#[derive(Debug, Clone, Serialize)]
+pub struct Problem {
+    #[serde(rename = "type")]
+    problem_type: String,
+    title: String,
+    status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    detail: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    instance: Option<String>,
+    #[serde(flatten)]
+    extensions: Map<String, Value>,
+}
+
+impl Problem {
+    //The title defaults to the reason phrase of the status.
+    pub fn new(status: StatusCode) -> Problem {
+        Problem {
+            problem_type: DEFAULT_TYPE.to_string(),
+            title: status
+                .canonical_reason()
+                .unwrap_or("Unknown error")
+                .to_string(),
+            status: status.as_u16(),
+            detail: None,
+            instance: None,
+            extensions: Map::new(),
+        }
+    }
+
+    pub fn with_type(mut self, problem_type: &str) -> Problem {
+        self.problem_type = problem_type.to_string();
+        self
+    }
+
+    pub fn with_title(mut self, title: &str) -> Problem {
+        self.title = title.to_string();
+        self
+    }
+
+    pub fn with_detail(mut self, detail: &str) -> Problem {
+        self.detail = Some(detail.to_string());
+        self
+    }
+
+    pub fn with_instance(mut self, instance: &str) -> Problem {
+        self.instance = Some(instance.to_string());
+        self
+    }
+
+    //Members that collide with the standard ones, or that can't be serialized, are ignored.
+    pub fn with_extension<T: Serialize>(mut self, name: &str, value: T) -> Problem {
+        if RESERVED_MEMBERS.contains(&name) {
+            tracing::warn!(name, "Ignoring problem extension with a reserved name.");
+            return self;
+        }
+        match serde_json::to_value(value) {
+            Ok(value) => {
+                self.extensions.insert(name.to_string(), value);
+            }
+            Err(e) => tracing::warn!(name, error = %e, "Couldn't serialize problem extension."),
+        }
+        self
+    }
+
+    pub fn status(&self) -> StatusCode {
+        StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR)
+    }
+
+    pub fn title(&self) -> &str {
+        &self.title
+    }
+
+    pub fn detail(&self) -> Option<&str> {
+        self.detail.as_deref()
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Should serialize problem.")
+    }
+
+    pub fn into_response(self) -> Response<HandlerBody> {
+        Response::builder()
+            .status(self.status())
+            .header(hyper::header::CONTENT_TYPE, CONTENT_TYPE_PROBLEM_JSON)
+            .body(bytes_to_boxed_body(self.to_json()))
+            .expect("Should produce response.")
+    }
+}
+
+//Implemented by application errors to choose the problem clients see.
+pub trait IntoProblem {
+    fn into_problem(self) -> Problem;
+
+    fn into_problem_response(self) -> Response<HandlerBody>
+    where
+        Self: Sized,
+    {
+        self.into_problem().into_response()
+    }
+}
+
+impl IntoProblem for Problem {
+    fn into_problem(self) -> Problem {
+        self
+    }
+}
+
+impl IntoProblem for StatusCode {
+    fn into_problem(self) -> Problem {
+        Problem::new(self)
+    }
+}
+
+//Lets handlers written against their own error type return a HandlerResult.
+pub fn problem_result<E: IntoProblem>(
+    result: Result<Response<HandlerBody>, E>,
+) -> HandlerResult {
+    match result {
+        Ok(response) => Ok(response),
+        Err(e) => Ok(e.into_problem_response()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn serializes_members_and_extensions() {
+        let problem = Problem::new(StatusCode::FORBIDDEN)
+            .with_type("https://example.com/probs/out-of-credit")
+            .with_detail("Your current balance is 30, but that costs 50.")
+            .with_instance("/account/12345/msgs/abc")
+            .with_extension("balance", 30)
+            .with_extension("status", 200);
+
+        let value: Value = serde_json::from_str(&problem.to_json()).unwrap();
+        assert_eq!(value["type"], "https://example.com/probs/out-of-credit");
+        assert_eq!(value["title"], "Forbidden");
+        assert_eq!(value["status"], 403);
+        assert_eq!(value["balance"], 30);
+        assert_eq!(value["instance"], "/account/12345/msgs/abc");
+
+        let response = problem.into_response();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        assert_eq!(
+            response.headers()[hyper::header::CONTENT_TYPE],
+            CONTENT_TYPE_PROBLEM_JSON
+        );
+    }
+
+    #[test]
+    fn omits_empty_optional_members() {
+        let value: Value =
+            serde_json::from_str(&Problem::new(StatusCode::NOT_FOUND).to_json()).unwrap();
+        assert_eq!(value["type"], "about:blank");
+        assert!(value.get("detail").is_none());
+        assert!(value.get("instance").is_none());
+    }
+}
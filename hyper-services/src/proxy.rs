@@ -0,0 +1,650 @@
+use std::{
+    net::IpAddr,
+    sync::{
+        atomic::{AtomicU32, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use http_body_util::BodyExt;
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Request, Response, StatusCode, Uri,
+};
+use hyper_rustls::{ConfigBuilderExt, HttpsConnector};
+use hyper_util::{
+    client::legacy::{connect::HttpConnector, Client},
+    rt::{TokioExecutor, TokioIo},
+};
+
+use crate::{
+    client::https_connector,
+    commons::{HandlerBody, HandlerError, HandlerResult},
+    problem::Problem,
+    request_id::RequestId,
+    response_building::empty_body,
+    service::{connection::ConnectionInfo, stateful_service::StatefulHandler},
+};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_FAILURE_THRESHOLD: u32 = 3;
+const DEFAULT_COOLDOWN: Duration = Duration::from_secs(10);
+
+//Connection-specific headers that must not be forwarded (RFC 9110 section 7.6.1).
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-authenticate",
+    "proxy-authorization",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+];
+
+pub type ProxyClient = Client<HttpsConnector<HttpConnector>, HandlerBody>;
+
+type PathRewrite = dyn Fn(&str) -> String + Send + Sync;
+
+struct Upstream {
+    scheme: String,
+    authority: String,
+    base_path: String,
+    consecutive_failures: AtomicU32,
+    unhealthy_until: Mutex<Option<Instant>>,
+}
+
+impl Upstream {
+    fn parse(uri: &str) -> Result<Upstream, HandlerError> {
+        let uri: Uri = uri.parse()?;
+        let scheme = match uri.scheme_str() {
+            Some(scheme @ ("http" | "https")) => scheme.to_string(),
+            _ => return Err(format!("Upstream {} must be an http or https URI.", uri).into()),
+        };
+        let authority = match uri.authority() {
+            Some(authority) => authority.to_string(),
+            None => return Err(format!("Upstream {} has no host.", uri).into()),
+        };
+        Ok(Upstream {
+            scheme,
+            authority,
+            base_path: uri.path().trim_end_matches('/').to_string(),
+            consecutive_failures: AtomicU32::new(0),
+            unhealthy_until: Mutex::new(None),
+        })
+    }
+
+    fn is_healthy(&self, now: Instant) -> bool {
+        match *self.unhealthy_until.lock().expect("Upstream lock poisoned.") {
+            Some(until) => until <= now,
+            None => true,
+        }
+    }
+
+    fn uri_for(&self, path_and_query: &str) -> Result<Uri, HandlerError> {
+        let uri = format!("{}://{}{}{}", self.scheme, self.authority, self.base_path, path_and_query);
+        Ok(uri.parse()?)
+    }
+}
+
+struct ProxyConfig {
+    upstreams: Vec<Upstream>,
+    next: AtomicUsize,
+    client: ProxyClient,
+    strip_prefix: Option<String>,
+    path_rewrite: Option<Box<PathRewrite>>,
+    preserve_host: bool,
+    timeout: Duration,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+//Forwards requests to one or more upstreams, picked round-robin. Upstreams that keep failing are skipped for a while.
+//Upgrades such as WebSockets are relayed when the server runs with ConnectionProperties::with_upgrades.
+#[derive(Clone)]
+pub struct ReverseProxy {
+    config: Arc<ProxyConfig>,
+}
+
+pub struct ReverseProxyBuilder {
+    upstreams: Vec<Upstream>,
+    tls_config: Option<rustls::ClientConfig>,
+    strip_prefix: Option<String>,
+    path_rewrite: Option<Box<PathRewrite>>,
+    preserve_host: bool,
+    timeout: Duration,
+    failure_threshold: u32,
+    cooldown: Duration,
+}
+
+impl ReverseProxy {
+    pub fn builder() -> ReverseProxyBuilder {
+        ReverseProxyBuilder {
+            upstreams: Vec::new(),
+            tls_config: None,
+            strip_prefix: None,
+            path_rewrite: None,
+            preserve_host: false,
+            timeout: DEFAULT_TIMEOUT,
+            failure_threshold: DEFAULT_FAILURE_THRESHOLD,
+            cooldown: DEFAULT_COOLDOWN,
+        }
+    }
+
+    //Forwards a request. Can be called from a handler that only proxies some routes.
+    pub async fn forward(&self, mut request: Request<Incoming>) -> HandlerResult {
+        let config = &self.config;
+        let index = match config.pick(Instant::now()) {
+            Some(index) => index,
+            None => return Ok(Problem::new(StatusCode::BAD_GATEWAY).into_response()),
+        };
+        let upstream = &config.upstreams[index];
+
+        let upgrade = requested_upgrade(request.headers());
+        let client_upgrade = upgrade.as_ref().map(|_| hyper::upgrade::on(&mut request));
+
+        let path_and_query = config.rewrite(&request);
+        let uri = upstream.uri_for(&path_and_query)?;
+        let (mut parts, body) = request.into_parts();
+        let original_host = parts
+            .headers
+            .get(header::HOST)
+            .cloned()
+            .or_else(|| parts.uri.authority().and_then(|authority| HeaderValue::from_str(authority.as_str()).ok()));
+
+        strip_hop_by_hop(&mut parts.headers);
+        if let Some(upgrade) = &upgrade {
+            parts.headers.insert(header::CONNECTION, HeaderValue::from_static("upgrade"));
+            parts.headers.insert(header::UPGRADE, upgrade.clone());
+        }
+        add_forwarded_headers(&mut parts, original_host.as_ref());
+        if !config.preserve_host {
+            parts.headers.insert(header::HOST, HeaderValue::from_str(&upstream.authority)?);
+        }
+        if let Some(request_id) = parts.extensions.get::<RequestId>().cloned() {
+            request_id.forward_headers(&mut parts.headers);
+        }
+        parts.uri = uri;
+        parts.version = hyper::Version::HTTP_11;
+
+        let body: HandlerBody = body.map_err(|e| Box::new(e) as HandlerError).boxed();
+        let outbound = Request::from_parts(parts, body);
+
+        tracing::debug!(upstream = upstream.authority, uri = %outbound.uri(), "Forwarding request.");
+        let mut response = match tokio::time::timeout(config.timeout, config.client.request(outbound)).await {
+            Ok(Ok(response)) => response,
+            Ok(Err(e)) => {
+                tracing::warn!(upstream = upstream.authority, error = %e, "Upstream request failed.");
+                config.record_failure(index);
+                return Ok(Problem::new(StatusCode::BAD_GATEWAY).into_response());
+            }
+            Err(_) => {
+                tracing::warn!(upstream = upstream.authority, "Upstream timed out.");
+                config.record_failure(index);
+                return Ok(Problem::new(StatusCode::GATEWAY_TIMEOUT).into_response());
+            }
+        };
+
+        match response.status() {
+            StatusCode::BAD_GATEWAY | StatusCode::SERVICE_UNAVAILABLE | StatusCode::GATEWAY_TIMEOUT => {
+                config.record_failure(index)
+            }
+            _ => config.record_success(index),
+        }
+
+        if let (StatusCode::SWITCHING_PROTOCOLS, Some(client_upgrade)) = (response.status(), client_upgrade) {
+            let upstream_upgrade = hyper::upgrade::on(&mut response);
+            tokio::spawn(async move {
+                match tokio::try_join!(client_upgrade, upstream_upgrade) {
+                    Ok((client, upstream)) => {
+                        let mut client = TokioIo::new(client);
+                        let mut upstream = TokioIo::new(upstream);
+                        if let Err(e) = tokio::io::copy_bidirectional(&mut client, &mut upstream).await {
+                            tracing::debug!(error = %e, "Upgraded proxy connection closed with an error.");
+                        }
+                    }
+                    Err(e) => tracing::warn!(error = %e, "Couldn't upgrade proxied connection."),
+                }
+            });
+            let (parts, _) = response.into_parts();
+            return Ok(Response::from_parts(parts, empty_body()));
+        }
+
+        let (mut parts, body) = response.into_parts();
+        strip_hop_by_hop(&mut parts.headers);
+        Ok(Response::from_parts(parts, body.map_err(|e| Box::new(e) as HandlerError).boxed()))
+    }
+}
+
+impl StatefulHandler for ReverseProxy {
+    async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+        self.forward(request).await
+    }
+}
+
+impl ReverseProxyBuilder {
+    pub fn upstream(mut self, uri: &str) -> Result<ReverseProxyBuilder, HandlerError> {
+        self.upstreams.push(Upstream::parse(uri)?);
+        Ok(self)
+    }
+
+    //Replaces the native root certificates, e.g. to trust a private CA.
+    pub fn with_tls_config(mut self, tls_config: rustls::ClientConfig) -> ReverseProxyBuilder {
+        self.tls_config = Some(tls_config);
+        self
+    }
+
+    //Removed from the start of the request path before it is appended to the upstream path.
+    pub fn with_strip_prefix(mut self, prefix: &str) -> ReverseProxyBuilder {
+        self.strip_prefix = Some(prefix.trim_end_matches('/').to_string());
+        self
+    }
+
+    //Applied to the path after the prefix was stripped.
+    pub fn with_path_rewrite<F>(mut self, rewrite: F) -> ReverseProxyBuilder
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.path_rewrite = Some(Box::new(rewrite));
+        self
+    }
+
+    //Sends the client's Host header instead of the upstream's.
+    pub fn with_preserve_host(mut self, preserve_host: bool) -> ReverseProxyBuilder {
+        self.preserve_host = preserve_host;
+        self
+    }
+
+    //Longest wait for the upstream response head.
+    pub fn with_timeout(mut self, timeout: Duration) -> ReverseProxyBuilder {
+        self.timeout = timeout;
+        self
+    }
+
+    //An upstream is skipped for the cooldown after this many consecutive failures.
+    pub fn with_passive_health(mut self, failure_threshold: u32, cooldown: Duration) -> ReverseProxyBuilder {
+        self.failure_threshold = failure_threshold.max(1);
+        self.cooldown = cooldown;
+        self
+    }
+
+    pub fn build(self) -> Result<ReverseProxy, HandlerError> {
+        if self.upstreams.is_empty() {
+            return Err("Reverse proxy needs at least one upstream.".into());
+        }
+        let tls_config = match self.tls_config {
+            Some(tls_config) => tls_config,
+            None => rustls::ClientConfig::builder()
+                .with_native_roots()?
+                .with_no_client_auth(),
+        };
+        let client = Client::builder(TokioExecutor::new()).build(https_connector(tls_config, None));
+
+        Ok(ReverseProxy {
+            config: Arc::new(ProxyConfig {
+                upstreams: self.upstreams,
+                next: AtomicUsize::new(0),
+                client,
+                strip_prefix: self.strip_prefix,
+                path_rewrite: self.path_rewrite,
+                preserve_host: self.preserve_host,
+                timeout: self.timeout,
+                failure_threshold: self.failure_threshold,
+                cooldown: self.cooldown,
+            }),
+        })
+    }
+}
+
+impl ProxyConfig {
+    //Next healthy upstream in turn. If none are healthy, the next one is tried anyway.
+    fn pick(&self, now: Instant) -> Option<usize> {
+        let count = self.upstreams.len();
+        if count == 0 {
+            return None;
+        }
+        let start = self.next.fetch_add(1, Ordering::Relaxed);
+        (0..count)
+            .map(|offset| (start + offset) % count)
+            .find(|index| self.upstreams[*index].is_healthy(now))
+            .or(Some(start % count))
+    }
+
+    fn record_failure(&self, index: usize) {
+        let upstream = &self.upstreams[index];
+        let failures = upstream.consecutive_failures.fetch_add(1, Ordering::Relaxed) + 1;
+        if failures >= self.failure_threshold {
+            tracing::warn!(upstream = upstream.authority, failures, "Marking upstream unhealthy.");
+            *upstream.unhealthy_until.lock().expect("Upstream lock poisoned.") = Some(Instant::now() + self.cooldown);
+            upstream.consecutive_failures.store(0, Ordering::Relaxed);
+        }
+    }
+
+    fn record_success(&self, index: usize) {
+        let upstream = &self.upstreams[index];
+        upstream.consecutive_failures.store(0, Ordering::Relaxed);
+        *upstream.unhealthy_until.lock().expect("Upstream lock poisoned.") = None;
+    }
+
+    fn rewrite<T>(&self, request: &Request<T>) -> String {
+        let mut path = request.uri().path();
+        if let Some(prefix) = &self.strip_prefix {
+            if let Some(stripped) = path.strip_prefix(prefix.as_str()) {
+                if stripped.is_empty() || stripped.starts_with('/') {
+                    path = stripped;
+                }
+            }
+        }
+        let mut path = match &self.path_rewrite {
+            Some(rewrite) => rewrite(path),
+            None => path.to_string(),
+        };
+        if !path.starts_with('/') {
+            path.insert(0, '/');
+        }
+        match request.uri().query() {
+            Some(query) => format!("{}?{}", path, query),
+            None => path,
+        }
+    }
+}
+
+fn requested_upgrade(headers: &HeaderMap) -> Option<HeaderValue> {
+    let connection_upgrade = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|token| token.trim().eq_ignore_ascii_case("upgrade"));
+    match connection_upgrade {
+        true => headers.get(header::UPGRADE).cloned(),
+        false => None,
+    }
+}
+
+//Removes the fixed hop-by-hop headers, Upgrade, and any header named in Connection.
+pub fn strip_hop_by_hop(headers: &mut HeaderMap) {
+    let named: Vec<HeaderName> = headers
+        .get_all(header::CONNECTION)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|name| HeaderName::from_bytes(name.trim().as_bytes()).ok())
+        .collect();
+    for name in named {
+        headers.remove(name);
+    }
+    for name in HOP_BY_HOP {
+        headers.remove(name);
+    }
+    headers.remove(header::UPGRADE);
+}
+
+//The incoming chain is only kept when the peer is a trusted proxy, otherwise it could be spoofed.
+fn add_forwarded_headers(parts: &mut hyper::http::request::Parts, original_host: Option<&HeaderValue>) {
+    let info = parts.extensions.get::<ConnectionInfo>().cloned();
+    //IPv4 clients of dual-stack listeners connect as ::ffff:a.b.c.d.
+    let peer = info.as_ref().and_then(|info| info.peer_addr).map(|peer| peer.ip().to_canonical());
+    let through_trusted_proxy = info.as_ref().is_some_and(|info| info.peer_is_trusted_proxy);
+    let proto = match info.as_ref().map(|info| info.tls) {
+        Some(true) => "https",
+        _ => "http",
+    };
+    let host = original_host.and_then(|host| host.to_str().ok());
+    let headers = &mut parts.headers;
+
+    let mut forwarded_for: Vec<String> = Vec::new();
+    let mut forwarded: Vec<String> = Vec::new();
+    if through_trusted_proxy {
+        forwarded_for.extend(joined(headers, "x-forwarded-for"));
+        forwarded.extend(joined(headers, "forwarded"));
+    }
+    headers.remove("x-forwarded-for");
+    headers.remove("x-forwarded-proto");
+    headers.remove("x-forwarded-host");
+    headers.remove(header::FORWARDED);
+
+    if let Some(peer) = peer {
+        forwarded_for.push(peer.to_string());
+        let mut element = format!("for={};proto={}", forwarded_node(peer), proto);
+        if let Some(host) = host {
+            element.push_str(&format!(";host={}", quoted_string(host)));
+        }
+        forwarded.push(element);
+    }
+    let mut insert = |name: &'static str, value: String| {
+        if let Ok(value) = HeaderValue::from_str(&value) {
+            headers.insert(name, value);
+        }
+    };
+    if !forwarded_for.is_empty() {
+        insert("x-forwarded-for", forwarded_for.join(", "));
+    }
+    if !forwarded.is_empty() {
+        insert("forwarded", forwarded.join(", "));
+    }
+    insert("x-forwarded-proto", proto.to_string());
+    if let Some(host) = host {
+        insert("x-forwarded-host", host.to_string());
+    }
+}
+
+//RFC 9110 section 5.6.4. Header values are visible ASCII already, so only quotes and backslashes need escaping.
+fn quoted_string(value: &str) -> String {
+    let mut quoted = String::with_capacity(value.len() + 2);
+    quoted.push('"');
+    for c in value.chars() {
+        if c == '"' || c == '\\' {
+            quoted.push('\\');
+        }
+        quoted.push(c);
+    }
+    quoted.push('"');
+    quoted
+}
+
+fn joined(headers: &HeaderMap, name: &str) -> Option<String> {
+    let values: Vec<&str> = headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .collect();
+    match values.is_empty() {
+        true => None,
+        false => Some(values.join(", ")),
+    }
+}
+
+fn forwarded_node(ip: IpAddr) -> String {
+    match ip {
+        IpAddr::V4(ip) => ip.to_string(),
+        IpAddr::V6(ip) => format!("\"[{}]\"", ip),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{convert::Infallible, net::SocketAddr};
+
+    use http_body_util::Full;
+    use hyper::{body::Bytes, server::conn::http1, service::service_fn};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+    use crate::{
+        request_processing::client_address::TrustedProxies,
+        service::{spawn::ConnectionProperties, stateful_service::StatefulService},
+        testing::TestClient,
+    };
+
+    fn proxy(upstreams: &[&str]) -> ReverseProxy {
+        let tls_config = rustls::ClientConfig::builder()
+            .with_root_certificates(rustls::RootCertStore::empty())
+            .with_no_client_auth();
+        let mut builder = ReverseProxy::builder()
+            .with_tls_config(tls_config)
+            .with_passive_health(2, Duration::from_secs(60));
+        for upstream in upstreams {
+            builder = builder.upstream(upstream).unwrap();
+        }
+        builder.with_strip_prefix("/api/").build().unwrap()
+    }
+
+    #[test]
+    fn rotates_and_skips_unhealthy_upstreams() {
+        let proxy = proxy(&["http://a:1", "http://b:2", "http://c:3"]);
+        let config = &proxy.config;
+        let now = Instant::now();
+        let picks: Vec<usize> = (0..4).map(|_| config.pick(now).unwrap()).collect();
+        assert_eq!(picks, [0, 1, 2, 0]);
+
+        config.record_failure(1);
+        assert_eq!(config.pick(now), Some(1));
+        config.record_failure(1);
+        let picks: Vec<usize> = (0..3).map(|_| config.pick(Instant::now()).unwrap()).collect();
+        assert_eq!(picks, [2, 0, 2]);
+
+        config.record_success(1);
+        assert!(config.upstreams[1].is_healthy(Instant::now()));
+    }
+
+    #[test]
+    fn rewrites_paths_and_headers() {
+        let proxy = proxy(&["https://backend:8443/v2/"]);
+        let request = Request::builder()
+            .uri("/api/items?page=2")
+            .body(())
+            .unwrap();
+        let path = proxy.config.rewrite(&request);
+        assert_eq!(
+            proxy.config.upstreams[0].uri_for(&path).unwrap(),
+            "https://backend:8443/v2/items?page=2"
+        );
+        let request = Request::builder().uri("/apiary").body(()).unwrap();
+        assert_eq!(proxy.config.rewrite(&request), "/apiary");
+
+        let mut headers = HeaderMap::new();
+        headers.insert(header::CONNECTION, "keep-alive, x-secret".parse().unwrap());
+        headers.insert("x-secret", "1".parse().unwrap());
+        headers.insert(header::TRANSFER_ENCODING, "chunked".parse().unwrap());
+        headers.insert(header::ACCEPT, "*/*".parse().unwrap());
+        strip_hop_by_hop(&mut headers);
+        assert_eq!(headers.len(), 1);
+        assert!(headers.contains_key(header::ACCEPT));
+    }
+
+    //Streams request bodies back, reports the forwarding headers it got, and echoes bytes on /upgrade.
+    async fn upstream() -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.unwrap();
+                let service = service_fn(|mut request: Request<Incoming>| async move {
+                    if request.uri().path() == "/upgrade" {
+                        let upgrade = hyper::upgrade::on(&mut request);
+                        tokio::spawn(async move {
+                            let (mut reader, mut writer) = tokio::io::split(TokioIo::new(upgrade.await.unwrap()));
+                            let _ = tokio::io::copy(&mut reader, &mut writer).await;
+                        });
+                        let response = Response::builder()
+                            .status(StatusCode::SWITCHING_PROTOCOLS)
+                            .header(header::CONNECTION, "upgrade")
+                            .header(header::UPGRADE, "websocket")
+                            .body(empty_body())
+                            .unwrap();
+                        return Ok::<_, Infallible>(response);
+                    }
+                    let mut response = Response::builder();
+                    for name in ["x-forwarded-for", "forwarded"] {
+                        if let Some(value) = request.headers().get(name) {
+                            response = response.header(format!("seen-{}", name), value);
+                        }
+                    }
+                    let body: HandlerBody = request.into_body().map_err(|e| Box::new(e) as HandlerError).boxed();
+                    Ok(response.body(body).unwrap())
+                });
+                tokio::spawn(http1::Builder::new().serve_connection(TokioIo::new(stream), service).with_upgrades());
+            }
+        });
+        format!("http://{}", address)
+    }
+
+    async fn proxy_client() -> TestClient<StatefulService<ReverseProxy>> {
+        let props = ConnectionProperties {
+            trusted_proxies: Some(TrustedProxies::new().trust("10.0.0.0/8").unwrap()),
+            with_upgrades: true,
+            ..Default::default()
+        };
+        TestClient::with_properties(StatefulService::create(proxy(&[&upstream().await])), props)
+    }
+
+    #[tokio::test]
+    async fn streams_bodies_through_the_upstream() {
+        let client = proxy_client().await;
+        let response = client.post("/api/echo").body("hello upstream").send().await;
+        response.assert_status(StatusCode::OK).assert_text("hello upstream");
+    }
+
+    #[tokio::test]
+    async fn drops_forwarding_headers_from_untrusted_peers() {
+        for peer in ["192.0.2.7:5000", "[::ffff:192.0.2.7]:5000"] {
+            let response = proxy_client()
+                .await
+                .with_peer_addr(peer.parse::<SocketAddr>().unwrap())
+                .get("/api/echo")
+                .header("x-forwarded-for", "198.51.100.1")
+                .header("forwarded", "for=198.51.100.1")
+                .send()
+                .await;
+            response
+                .assert_status(StatusCode::OK)
+                .assert_header("seen-x-forwarded-for", "192.0.2.7")
+                .assert_header("seen-forwarded", "for=192.0.2.7;proto=http;host=\"localhost\"");
+        }
+
+        let response = proxy_client()
+            .await
+            .with_peer_addr("10.0.0.1:5000".parse().unwrap())
+            .get("/api/echo")
+            .header("x-forwarded-for", "198.51.100.1")
+            .send()
+            .await;
+        response.assert_header("seen-x-forwarded-for", "198.51.100.1, 10.0.0.1");
+    }
+
+    #[tokio::test]
+    async fn quotes_the_forwarded_host() {
+        let client = proxy_client().await;
+        let response = client
+            .get("/api/echo")
+            .header(header::HOST, "evil\";for=\\1.2.3.4")
+            .send()
+            .await;
+        response.assert_header("seen-forwarded", "for=127.0.0.1;proto=http;host=\"evil\\\";for=\\\\1.2.3.4\"");
+    }
+
+    #[tokio::test]
+    async fn relays_upgraded_connections() {
+        let client = proxy_client().await;
+        let request = Request::builder()
+            .uri("/api/upgrade")
+            .header(header::HOST, "localhost")
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .body(Full::new(Bytes::new()))
+            .unwrap();
+        let response = client.send(request).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        let mut upgraded = TokioIo::new(hyper::upgrade::on(response).await.unwrap());
+        upgraded.write_all(b"ping").await.unwrap();
+        let mut echoed = [0; 4];
+        upgraded.read_exact(&mut echoed).await.unwrap();
+        assert_eq!(&echoed, b"ping");
+    }
+}
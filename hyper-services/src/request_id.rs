@@ -0,0 +1,207 @@
+use hyper::{header::HeaderValue, HeaderMap, Request};
+
+pub const HEADER_X_REQUEST_ID: &str = "X-Request-Id";
+pub const HEADER_TRACEPARENT: &str = "traceparent";
+
+const MAX_REQUEST_ID_LENGTH: usize = 128;
+
+//W3C trace context as carried by the traceparent header.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: String,
+    pub parent_id: String,
+    pub flags: u8,
+}
+
+//Identifies a request across services. Inserted into the request extensions by the service spawner and echoed on the response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RequestId {
+    id: String,
+    trace: TraceContext,
+}
+
+impl RequestId {
+    //Uses X-Request-Id if present, otherwise the trace id of traceparent, otherwise a new random id.
+    pub fn from_headers(headers: &HeaderMap) -> RequestId {
+        let incoming_trace = headers
+            .get(HEADER_TRACEPARENT)
+            .and_then(|value| value.to_str().ok())
+            .and_then(parse_traceparent);
+        let incoming_id = headers
+            .get(HEADER_X_REQUEST_ID)
+            .and_then(|value| value.to_str().ok())
+            .filter(|id| is_valid_request_id(id));
+
+        match (incoming_id, incoming_trace) {
+            (Some(id), Some(trace)) => RequestId {
+                id: id.to_string(),
+                trace,
+            },
+            (Some(id), None) => RequestId {
+                id: id.to_string(),
+                trace: new_root_trace(),
+            },
+            (None, Some(trace)) => RequestId {
+                id: trace.trace_id.clone(),
+                trace,
+            },
+            (None, None) => RequestId::generate(),
+        }
+    }
+
+    pub fn generate() -> RequestId {
+        let trace = new_root_trace();
+        RequestId {
+            id: trace.trace_id.clone(),
+            trace,
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.id
+    }
+
+    pub fn trace_context(&self) -> &TraceContext {
+        &self.trace
+    }
+
+    //Adds X-Request-Id and a child traceparent to the headers of an outbound request.
+    pub fn forward_headers(&self, headers: &mut HeaderMap) {
+        if let Ok(value) = HeaderValue::from_str(&self.id) {
+            headers.insert(HEADER_X_REQUEST_ID, value);
+        }
+        let traceparent = format!(
+            "00-{}-{}-{:02x}",
+            self.trace.trace_id,
+            random_hex::<8>(),
+            self.trace.flags
+        );
+        if let Ok(value) = HeaderValue::from_str(&traceparent) {
+            headers.insert(HEADER_TRACEPARENT, value);
+        }
+    }
+}
+
+impl std::fmt::Display for RequestId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&self.id)
+    }
+}
+
+pub fn request_id<T>(request: &Request<T>) -> Option<&RequestId> {
+    request.extensions().get::<RequestId>()
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LENGTH
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+//00-<32 hex trace id>-<16 hex parent id>-<2 hex flags>
+fn parse_traceparent(value: &str) -> Option<TraceContext> {
+    let fields: Vec<&str> = value.trim().split('-').collect();
+    if fields.len() < 4 {
+        return None;
+    }
+    let (version, trace_id, parent_id, flags) = (fields[0], fields[1], fields[2], fields[3]);
+
+    let is_lower_hex = |field: &str, length: usize| {
+        field.len() == length
+            && field
+                .bytes()
+                .all(|byte| byte.is_ascii_digit() || (b'a'..=b'f').contains(&byte))
+    };
+    let is_zero = |field: &str| field.bytes().all(|byte| byte == b'0');
+
+    if !is_lower_hex(version, 2) || version == "ff" || (version == "00" && fields.len() != 4) {
+        return None;
+    }
+    if !is_lower_hex(trace_id, 32) || is_zero(trace_id) {
+        return None;
+    }
+    if !is_lower_hex(parent_id, 16) || is_zero(parent_id) {
+        return None;
+    }
+    if !is_lower_hex(flags, 2) {
+        return None;
+    }
+
+    Some(TraceContext {
+        trace_id: trace_id.to_string(),
+        parent_id: parent_id.to_string(),
+        flags: u8::from_str_radix(flags, 16).ok()?,
+    })
+}
+
+fn new_root_trace() -> TraceContext {
+    TraceContext {
+        trace_id: random_hex::<16>(),
+        parent_id: random_hex::<8>(),
+        flags: 0,
+    }
+}
+
+fn random_hex<const N: usize>() -> String {
+    let bytes: [u8; N] = rand::random();
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TRACEPARENT: &str = "00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01";
+
+    #[test]
+    fn prefers_x_request_id() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_X_REQUEST_ID, "abc-123".parse().unwrap());
+        headers.insert(HEADER_TRACEPARENT, TRACEPARENT.parse().unwrap());
+        let request_id = RequestId::from_headers(&headers);
+        assert_eq!(request_id.as_str(), "abc-123");
+        assert_eq!(
+            request_id.trace_context().trace_id,
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+    }
+
+    #[test]
+    fn falls_back_to_traceparent() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_X_REQUEST_ID, "has a space".parse().unwrap());
+        headers.insert(HEADER_TRACEPARENT, TRACEPARENT.parse().unwrap());
+        let request_id = RequestId::from_headers(&headers);
+        assert_eq!(request_id.as_str(), "4bf92f3577b34da6a3ce929d0e0e4736");
+        assert_eq!(request_id.trace_context().flags, 1);
+    }
+
+    #[test]
+    fn rejects_malformed_traceparent() {
+        assert!(parse_traceparent("00-00000000000000000000000000000000-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("ff-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4BF92F3577B34DA6A3CE929D0E0E4736-00f067aa0ba902b7-01").is_none());
+        assert!(parse_traceparent("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7").is_none());
+    }
+
+    #[test]
+    fn forwards_a_child_span() {
+        let mut headers = HeaderMap::new();
+        headers.insert(HEADER_TRACEPARENT, TRACEPARENT.parse().unwrap());
+        let request_id = RequestId::from_headers(&headers);
+
+        let mut outbound = HeaderMap::new();
+        request_id.forward_headers(&mut outbound);
+        let forwarded = parse_traceparent(outbound[HEADER_TRACEPARENT].to_str().unwrap()).unwrap();
+        assert_eq!(forwarded.trace_id, request_id.trace_context().trace_id);
+        assert_ne!(forwarded.parent_id, request_id.trace_context().parent_id);
+        assert_eq!(outbound[HEADER_X_REQUEST_ID], request_id.as_str());
+    }
+
+    #[test]
+    fn generates_when_missing() {
+        let request_id = RequestId::from_headers(&HeaderMap::new());
+        assert_eq!(request_id.as_str().len(), 32);
+        assert_ne!(request_id, RequestId::generate());
+    }
+}
@@ -0,0 +1,167 @@
+use std::net::IpAddr;
+
+use hyper::HeaderMap;
+use ipnet::IpNet;
+
+use crate::access_control::parse_network;
+
+const HEADER_FORWARDED: &str = "Forwarded";
+const HEADER_X_FORWARDED_FOR: &str = "X-Forwarded-For";
+
+//The client address after proxy resolution. Inserted into the request extensions unless the peer has no address,
+//like Unix socket peers without ConnectionProperties::unix_peer_addr.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClientAddr(pub IpAddr);
+
+//Proxies whose Forwarded / X-Forwarded-For headers are believed.
+#[derive(Debug, Clone, Default)]
+pub struct TrustedProxies {
+    networks: Vec<IpNet>,
+}
+
+impl TrustedProxies {
+    pub fn new() -> TrustedProxies {
+        TrustedProxies::default()
+    }
+
+    pub fn trust(mut self, cidr: &str) -> Result<TrustedProxies, ipnet::AddrParseError> {
+        self.networks.push(parse_network(cidr)?);
+        Ok(self)
+    }
+
+    pub fn trust_network(mut self, network: IpNet) -> TrustedProxies {
+        self.networks.push(network);
+        self
+    }
+
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        let ip = ip.to_canonical();
+        self.networks.iter().any(|network| network.contains(&ip))
+    }
+}
+
+//Walks the forwarding chain from the nearest hop outward and returns the first address that isn't a trusted proxy.
+pub fn resolve_client_addr(
+    headers: &HeaderMap,
+    peer: IpAddr,
+    trusted_proxies: Option<&TrustedProxies>,
+) -> IpAddr {
+    let peer = peer.to_canonical();
+    let trusted_proxies = match trusted_proxies {
+        Some(trusted_proxies) if trusted_proxies.contains(peer) => trusted_proxies,
+        _ => return peer,
+    };
+
+    let chain = forwarded_chain(headers);
+    if chain.is_empty() {
+        return peer;
+    }
+
+    let mut client = peer;
+    for hop in chain.iter().rev() {
+        match hop {
+            Some(ip) => {
+                client = ip.to_canonical();
+                if !trusted_proxies.contains(client) {
+                    break;
+                }
+            }
+            //Obfuscated or unparseable hop. Nothing beyond it can be trusted.
+            None => break,
+        }
+    }
+    client
+}
+
+//Prefers the standard Forwarded header and falls back to X-Forwarded-For.
+fn forwarded_chain(headers: &HeaderMap) -> Vec<Option<IpAddr>> {
+    let forwarded: Vec<Option<IpAddr>> = headers
+        .get_all(HEADER_FORWARDED)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .filter_map(|element| {
+            element.split(';').find_map(|pair| {
+                let (key, value) = pair.trim().split_once('=')?;
+                match key.trim().eq_ignore_ascii_case("for") {
+                    true => Some(parse_forwarded_node(value.trim())),
+                    false => None,
+                }
+            })
+        })
+        .collect();
+
+    if !forwarded.is_empty() {
+        return forwarded;
+    }
+
+    headers
+        .get_all(HEADER_X_FORWARDED_FOR)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .map(|hop| parse_forwarded_node(hop.trim()))
+        .collect()
+}
+
+//Accepts 192.0.2.1, 192.0.2.1:8080, "[2001:db8::1]:4711" and bare IPv6 addresses.
+fn parse_forwarded_node(node: &str) -> Option<IpAddr> {
+    let node = node.trim_matches('"');
+    if let Ok(ip) = node.parse::<IpAddr>() {
+        return Some(ip);
+    }
+    if let Some(rest) = node.strip_prefix('[') {
+        let (ip, _) = rest.split_once(']')?;
+        return ip.parse().ok();
+    }
+    let (ip, _port) = node.rsplit_once(':')?;
+    ip.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(name: &'static str, value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(name, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn untrusted_peer_is_the_client() {
+        let proxies = TrustedProxies::new().trust("10.0.0.0/8").unwrap();
+        let peer: IpAddr = "192.0.2.7".parse().unwrap();
+        let resolved = resolve_client_addr(
+            &headers(HEADER_X_FORWARDED_FOR, "198.51.100.1"),
+            peer,
+            Some(&proxies),
+        );
+        assert_eq!(resolved, peer);
+    }
+
+    #[test]
+    fn skips_trusted_hops() {
+        let proxies = TrustedProxies::new().trust("10.0.0.0/8").unwrap();
+        let resolved = resolve_client_addr(
+            &headers(HEADER_X_FORWARDED_FOR, "203.0.113.9, 198.51.100.1, 10.0.0.2"),
+            "10.0.0.1".parse().unwrap(),
+            Some(&proxies),
+        );
+        assert_eq!(resolved, "198.51.100.1".parse::<IpAddr>().unwrap());
+    }
+
+    #[test]
+    fn parses_forwarded_header() {
+        let proxies = TrustedProxies::new().trust("::1").unwrap();
+        let resolved = resolve_client_addr(
+            &headers(
+                HEADER_FORWARDED,
+                "for=192.0.2.60;proto=http, for=\"[2001:db8:cafe::17]:4711\"",
+            ),
+            "::1".parse().unwrap(),
+            Some(&proxies),
+        );
+        assert_eq!(resolved, "2001:db8:cafe::17".parse::<IpAddr>().unwrap());
+    }
+}
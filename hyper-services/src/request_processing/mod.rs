@@ -1,3 +1,6 @@
+pub mod client_address;
+pub mod streaming;
+
 use base64::Engine;
 use http_body_util::BodyExt;
 use hyper::{body::Incoming, Response};
@@ -6,6 +9,7 @@ use crate::{
     commons::{Handler, HandlerError}, response_building::empty_body,
 };
 
+//Buffers the whole body. See streaming for incremental reads with limits.
 pub async fn collect_incoming(request: Incoming) -> Result<http_body_util::Collected<hyper::body::Bytes>, HandlerError> {
     match request.collect().await
     {
@@ -40,24 +44,25 @@ fn basic_authentication_decode(encoded: &str) -> Option<Auth> {
                 match split.len() {
                     2 => Some(Auth{user:split[0].to_string(), password:split[1].to_string()}),
                     _ => {
-                        eprintln!("Wrong number of split entries in {}", result);
+                        tracing::warn!(entries = split.len(), "Wrong number of split entries in basic authentication credentials.");
                         None
                     }
                 }
             }
             Err(e) => {
-                eprintln!("{:?}", e);
+                tracing::warn!(error = %e, "Basic authentication credentials aren't valid UTF-8.");
                 None
             }
         },
         Err(e) => {
-            eprintln!("{:?}", e);
+            tracing::warn!(error = %e, "Couldn't decode basic authentication credentials.");
             None
         }
     }
 }
 
 fn unauthorized_response(realm: &str) -> Handler {
+    tracing::debug!(realm, "Basic authentication failed.");
     Handler::ImmediateReturn(
         Response::builder()
             .status(hyper::StatusCode::UNAUTHORIZED)
@@ -70,6 +75,7 @@ fn unauthorized_response(realm: &str) -> Handler {
     )
 }
 
+#[tracing::instrument(level = "debug", skip(request_parts, validator))]
 pub async fn check_basic_authentication(
     request_parts: &hyper::http::request::Parts,
     realm: &str,
@@ -87,8 +93,10 @@ pub async fn check_basic_authentication(
                         match basic_authentication_decode(auth_words[1])
                         {
                             Some(auth) => {
+                                let user = auth.user.clone();
                                 if validator(auth)
                                 {
+                                    tracing::debug!(user, "Basic authentication succeeded.");
                                     Handler::Continue
                                 }
                                 else {
@@ -0,0 +1,300 @@
+use std::time::Duration;
+
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use http_body_util::BodyExt;
+use hyper::body::{Body, Bytes};
+use serde::de::DeserializeOwned;
+use tokio::{io::AsyncRead, time::Instant};
+use tokio_util::io::StreamReader;
+
+use crate::{commons::HandlerError, http_error::HttpError};
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_MAX_LINE_LENGTH: usize = 1024 * 1024;
+
+//Limits applied while a request body is read. Exceeding them fails the read with an HttpError (413 or 408).
+#[derive(Debug, Clone)]
+pub struct BodyLimits {
+    pub max_bytes: Option<u64>,
+    //Longest wait for the next chunk, so stalled clients don't hold the handler forever.
+    pub idle_timeout: Option<Duration>,
+    //Longest time for the whole body.
+    pub total_timeout: Option<Duration>,
+    //Only applies to the line and NDJSON readers.
+    pub max_line_length: usize,
+}
+
+impl Default for BodyLimits {
+    fn default() -> Self {
+        BodyLimits {
+            max_bytes: None,
+            idle_timeout: Some(DEFAULT_IDLE_TIMEOUT),
+            total_timeout: None,
+            max_line_length: DEFAULT_MAX_LINE_LENGTH,
+        }
+    }
+}
+
+impl BodyLimits {
+    pub fn with_max_bytes(mut self, max_bytes: u64) -> BodyLimits {
+        self.max_bytes = Some(max_bytes);
+        self
+    }
+
+    pub fn with_idle_timeout(mut self, idle_timeout: Option<Duration>) -> BodyLimits {
+        self.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn with_total_timeout(mut self, total_timeout: Duration) -> BodyLimits {
+        self.total_timeout = Some(total_timeout);
+        self
+    }
+
+    pub fn with_max_line_length(mut self, max_line_length: usize) -> BodyLimits {
+        self.max_line_length = max_line_length;
+        self
+    }
+}
+
+struct ReadState<B> {
+    body: B,
+    limits: BodyLimits,
+    received: u64,
+    deadline: Option<Instant>,
+    done: bool,
+}
+
+//Data chunks as they arrive. The next chunk is only read from the connection once the previous one was taken.
+pub fn body_stream<B>(body: B, limits: BodyLimits) -> impl Stream<Item = Result<Bytes, HandlerError>> + Send + 'static
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<HandlerError>,
+{
+    //A declared length over the limit is refused before anything is read.
+    let declared_too_large = match (limits.max_bytes, body.size_hint().exact()) {
+        (Some(max_bytes), Some(exact)) => exact > max_bytes,
+        _ => false,
+    };
+    let state = ReadState {
+        deadline: limits.total_timeout.map(|total| Instant::now() + total),
+        body,
+        limits,
+        received: 0,
+        done: false,
+    };
+
+    stream::unfold(state, move |mut state| async move {
+        if state.done {
+            return None;
+        }
+        if declared_too_large {
+            state.done = true;
+            return Some((Err(too_large()), state));
+        }
+        loop {
+            let wait_until = match (state.limits.idle_timeout, state.deadline) {
+                (Some(idle), Some(deadline)) => Some(deadline.min(Instant::now() + idle)),
+                (Some(idle), None) => Some(Instant::now() + idle),
+                (None, deadline) => deadline,
+            };
+            let frame = match wait_until {
+                Some(wait_until) => match tokio::time::timeout_at(wait_until, state.body.frame()).await {
+                    Ok(frame) => frame,
+                    Err(_) => {
+                        state.done = true;
+                        tracing::debug!(received = state.received, "Request body timed out.");
+                        let error: HandlerError = Box::new(HttpError::request_timeout("Request body wasn't received in time."));
+                        return Some((Err(error), state));
+                    }
+                },
+                None => state.body.frame().await,
+            };
+            match frame {
+                Some(Ok(frame)) => {
+                    //Trailers are skipped.
+                    if let Ok(data) = frame.into_data() {
+                        state.received += data.len() as u64;
+                        if let Some(max_bytes) = state.limits.max_bytes {
+                            if state.received > max_bytes {
+                                state.done = true;
+                                return Some((Err(too_large()), state));
+                            }
+                        }
+                        return Some((Ok(data), state));
+                    }
+                }
+                Some(Err(e)) => {
+                    state.done = true;
+                    return Some((Err(e.into()), state));
+                }
+                None => return None,
+            }
+        }
+    })
+}
+
+fn too_large() -> HandlerError {
+    Box::new(HttpError::payload_too_large("Request body is too large."))
+}
+
+//Like collect_incoming, with limits.
+pub async fn collect_limited<B>(body: B, limits: BodyLimits) -> Result<Bytes, HandlerError>
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<HandlerError>,
+{
+    let chunks: Vec<Bytes> = body_stream(body, limits).try_collect().await?;
+    Ok(Bytes::from(chunks.concat()))
+}
+
+//Errors keep the HttpError as the inner error of the io::Error.
+pub fn body_reader<B>(body: B, limits: BodyLimits) -> impl AsyncRead + Send + 'static
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<HandlerError>,
+{
+    StreamReader::new(Box::pin(body_stream(body, limits).map_err(std::io::Error::other)))
+}
+
+struct LineState<S> {
+    chunks: S,
+    buffer: Vec<u8>,
+    max_line_length: usize,
+    done: bool,
+}
+
+//Lines separated by \n, with any trailing \r removed.
+pub fn body_lines<B>(body: B, limits: BodyLimits) -> impl Stream<Item = Result<String, HandlerError>> + Send + 'static
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<HandlerError>,
+{
+    let state = LineState {
+        max_line_length: limits.max_line_length,
+        chunks: Box::pin(body_stream(body, limits)),
+        buffer: Vec::new(),
+        done: false,
+    };
+
+    stream::unfold(state, |mut state| async move {
+        loop {
+            if let Some(position) = state.buffer.iter().position(|byte| *byte == b'\n') {
+                let mut line: Vec<u8> = state.buffer.drain(..=position).collect();
+                line.pop();
+                return Some((line_to_string(line, state.max_line_length), state));
+            }
+            if state.done {
+                return match state.buffer.is_empty() {
+                    true => None,
+                    false => {
+                        let line = std::mem::take(&mut state.buffer);
+                        Some((line_to_string(line, state.max_line_length), state))
+                    }
+                };
+            }
+            if state.buffer.len() > state.max_line_length {
+                state.done = true;
+                state.buffer.clear();
+                let error: HandlerError = Box::new(HttpError::payload_too_large("Request body line is too long."));
+                return Some((Err(error), state));
+            }
+            match state.chunks.next().await {
+                Some(Ok(chunk)) => state.buffer.extend_from_slice(&chunk),
+                Some(Err(e)) => {
+                    state.done = true;
+                    state.buffer.clear();
+                    return Some((Err(e), state));
+                }
+                None => state.done = true,
+            }
+        }
+    })
+}
+
+fn line_to_string(mut line: Vec<u8>, max_line_length: usize) -> Result<String, HandlerError> {
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    if line.len() > max_line_length {
+        return Err(Box::new(HttpError::payload_too_large("Request body line is too long.")));
+    }
+    String::from_utf8(line)
+        .map_err(|e| Box::new(HttpError::bad_request("Request body isn't valid UTF-8.").with_source(e)) as HandlerError)
+}
+
+//One JSON document per line. Blank lines are skipped.
+pub fn body_ndjson<B, T>(body: B, limits: BodyLimits) -> impl Stream<Item = Result<T, HandlerError>> + Send + 'static
+where
+    B: Body<Data = Bytes> + Send + Unpin + 'static,
+    B::Error: Into<HandlerError>,
+    T: DeserializeOwned + Send + 'static,
+{
+    body_lines(body, limits)
+        .enumerate()
+        .filter(|(_, line)| {
+            let blank = matches!(line, Ok(line) if line.trim().is_empty());
+            async move { !blank }
+        })
+        .map(|(index, line)| {
+            let line = line?;
+            serde_json::from_str::<T>(&line).map_err(|e| {
+                let message = format!("Line {} isn't valid JSON.", index + 1);
+                Box::new(HttpError::bad_request(&message).with_source(e)) as HandlerError
+            })
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{channel::Channel, Full};
+
+    use super::*;
+    use crate::http_error::HttpErrorKind;
+
+    fn kind(error: &HandlerError) -> Option<HttpErrorKind> {
+        error.downcast_ref::<HttpError>().map(|error| error.kind())
+    }
+
+    #[tokio::test]
+    async fn reads_lines_and_ndjson() {
+        let body = Full::new(Bytes::from("{\"a\":1}\r\n\n{\"a\":2}"));
+        let values: Vec<serde_json::Value> = body_ndjson(body, BodyLimits::default())
+            .try_collect()
+            .await
+            .unwrap();
+        assert_eq!(values, [serde_json::json!({"a":1}), serde_json::json!({"a":2})]);
+
+        let body = Full::new(Bytes::from("ok\nnot json\n"));
+        let results: Vec<Result<serde_json::Value, HandlerError>> =
+            body_ndjson(body, BodyLimits::default()).collect().await;
+        assert_eq!(kind(results[0].as_ref().unwrap_err()), Some(HttpErrorKind::BadRequest));
+    }
+
+    #[tokio::test]
+    async fn enforces_limits() {
+        let body = Full::new(Bytes::from(vec![0u8; 100]));
+        let error = collect_limited(body, BodyLimits::default().with_max_bytes(10))
+            .await
+            .unwrap_err();
+        assert_eq!(kind(&error), Some(HttpErrorKind::PayloadTooLarge));
+
+        let body = Full::new(Bytes::from("a very long line\n"));
+        let mut lines = Box::pin(body_lines(body, BodyLimits::default().with_max_line_length(4)));
+        let error = lines.next().await.unwrap().unwrap_err();
+        assert_eq!(kind(&error), Some(HttpErrorKind::PayloadTooLarge));
+    }
+
+    #[tokio::test]
+    async fn times_out_stalled_bodies() {
+        let (mut sender, body) = Channel::<Bytes, HandlerError>::new(1);
+        let limits = BodyLimits::default().with_idle_timeout(Some(Duration::from_millis(50)));
+        let mut chunks = Box::pin(body_stream(body, limits));
+
+        sender.send_data(Bytes::from("first")).await.unwrap();
+        assert_eq!(chunks.next().await.unwrap().unwrap(), "first");
+        let error = chunks.next().await.unwrap().unwrap_err();
+        assert_eq!(kind(&error), Some(HttpErrorKind::RequestTimeout));
+        assert!(chunks.next().await.is_none());
+    }
+}
@@ -0,0 +1,322 @@
+use async_compression::tokio::bufread::{BrotliEncoder, DeflateEncoder, GzipEncoder, ZstdEncoder};
+use futures_util::TryStreamExt;
+use http_body_util::BodyExt;
+use hyper::{
+    body::Body,
+    header::{self, HeaderValue},
+    HeaderMap, Response, StatusCode,
+};
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+use crate::{commons::HandlerBody, response_building::streaming::async_read_to_boxed_body};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ContentCoding {
+    Brotli,
+    Zstd,
+    Gzip,
+    Deflate,
+}
+
+impl ContentCoding {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ContentCoding::Brotli => "br",
+            ContentCoding::Zstd => "zstd",
+            ContentCoding::Gzip => "gzip",
+            ContentCoding::Deflate => "deflate",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct CompressionConfig {
+    //In order of preference when the client accepts several with the same quality.
+    pub codings: Vec<ContentCoding>,
+    //Bodies known to be smaller than this are sent as is. Streams of unknown length are always compressed.
+    pub min_size: u64,
+    //Content type prefixes that are already compressed.
+    pub skip_content_types: Vec<String>,
+}
+
+impl Default for CompressionConfig {
+    fn default() -> Self {
+        CompressionConfig {
+            codings: vec![
+                ContentCoding::Brotli,
+                ContentCoding::Zstd,
+                ContentCoding::Gzip,
+                ContentCoding::Deflate,
+            ],
+            min_size: 1024,
+            skip_content_types: [
+                "image/",
+                "video/",
+                "audio/",
+                "font/woff",
+                "application/zip",
+                "application/gzip",
+                "application/x-gzip",
+                "application/zstd",
+                "application/x-7z-compressed",
+                "application/x-rar-compressed",
+                "application/pdf",
+                "application/octet-stream",
+                "text/event-stream",
+            ]
+            .iter()
+            .map(|content_type| content_type.to_string())
+            .collect(),
+        }
+    }
+}
+
+//Picks the coding with the highest quality value in Accept-Encoding. None means identity.
+pub fn negotiate(accept_encoding: &str, supported: &[ContentCoding]) -> Option<ContentCoding> {
+    let mut wildcard: Option<f32> = None;
+    let mut explicit: Vec<(String, f32)> = Vec::new();
+    for entry in accept_encoding.split(',') {
+        let mut params = entry.split(';');
+        let name = params.next().unwrap_or("").trim().to_ascii_lowercase();
+        if name.is_empty() {
+            continue;
+        }
+        let quality = params
+            .filter_map(|param| {
+                let (key, value) = param.split_once('=')?;
+                match key.trim().eq_ignore_ascii_case("q") {
+                    true => value.trim().parse::<f32>().ok(),
+                    false => None,
+                }
+            })
+            .next()
+            .unwrap_or(1.0);
+        match name.as_str() {
+            "*" => wildcard = Some(quality),
+            _ => explicit.push((name, quality)),
+        }
+    }
+
+    let quality_of = |coding: &ContentCoding| {
+        let aliases: &[&str] = match coding {
+            ContentCoding::Gzip => &["gzip", "x-gzip"],
+            other => &[other.as_str()],
+        };
+        explicit
+            .iter()
+            .find(|(name, _)| aliases.contains(&name.as_str()))
+            .map(|(_, quality)| *quality)
+            .or(wildcard)
+            .unwrap_or(0.0)
+    };
+
+    let mut best: Option<(ContentCoding, f32)> = None;
+    for coding in supported {
+        let quality = quality_of(coding);
+        if quality <= 0.0 {
+            continue;
+        }
+        match best {
+            Some((_, best_quality)) if best_quality >= quality => (),
+            _ => best = Some((*coding, quality)),
+        }
+    }
+    best.map(|(coding, _)| coding)
+}
+
+//Post-processor for handler responses. Pass the headers of the request the response answers.
+pub fn compress_response(
+    request_headers: &HeaderMap,
+    response: Response<HandlerBody>,
+    config: &CompressionConfig,
+) -> Response<HandlerBody> {
+    if !is_compressible(&response, config) {
+        return response;
+    }
+
+    let mut response = response;
+    add_vary_accept_encoding(response.headers_mut());
+
+    if let Some(exact) = response.body().size_hint().exact() {
+        if exact < config.min_size {
+            return response;
+        }
+    }
+
+    let accept_encoding = request_headers
+        .get(header::ACCEPT_ENCODING)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("");
+    let coding = match negotiate(accept_encoding, &config.codings) {
+        Some(coding) => coding,
+        None => return response,
+    };
+
+    let (mut parts, body) = response.into_parts();
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::ACCEPT_RANGES);
+    parts.headers.insert(
+        header::CONTENT_ENCODING,
+        HeaderValue::from_static(coding.as_str()),
+    );
+    //The representation changed, so a strong validator no longer holds.
+    if let Some(etag) = parts.headers.get(header::ETAG).cloned() {
+        if !etag.as_bytes().starts_with(b"W/") {
+            let mut weak = b"W/".to_vec();
+            weak.extend_from_slice(etag.as_bytes());
+            if let Ok(weak) = HeaderValue::from_bytes(&weak) {
+                parts.headers.insert(header::ETAG, weak);
+            }
+        }
+    }
+
+    Response::from_parts(parts, compress_body(body, coding))
+}
+
+pub fn compress_body(body: HandlerBody, coding: ContentCoding) -> HandlerBody {
+    let reader = StreamReader::new(body.into_data_stream().map_err(std::io::Error::other));
+    let encoder: Box<dyn AsyncRead + Send + Unpin> = match coding {
+        ContentCoding::Brotli => Box::new(BrotliEncoder::new(reader)),
+        ContentCoding::Zstd => Box::new(ZstdEncoder::new(reader)),
+        ContentCoding::Gzip => Box::new(GzipEncoder::new(reader)),
+        ContentCoding::Deflate => Box::new(DeflateEncoder::new(reader)),
+    };
+    async_read_to_boxed_body(encoder)
+}
+
+fn is_compressible(response: &Response<HandlerBody>, config: &CompressionConfig) -> bool {
+    let status = response.status();
+    if status.is_informational()
+        || status == StatusCode::NO_CONTENT
+        || status == StatusCode::NOT_MODIFIED
+        || status == StatusCode::PARTIAL_CONTENT
+    {
+        return false;
+    }
+
+    let headers = response.headers();
+    if headers.contains_key(header::CONTENT_ENCODING) || response.body().is_end_stream() {
+        return false;
+    }
+    if let Some(cache_control) = headers
+        .get(header::CACHE_CONTROL)
+        .and_then(|value| value.to_str().ok())
+    {
+        if cache_control.to_ascii_lowercase().contains("no-transform") {
+            return false;
+        }
+    }
+
+    let content_type = headers
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("")
+        .trim()
+        .to_ascii_lowercase();
+    !config
+        .skip_content_types
+        .iter()
+        .any(|skipped| content_type.starts_with(skipped.as_str()))
+        || content_type.starts_with("image/svg+xml")
+}
+
+fn add_vary_accept_encoding(headers: &mut HeaderMap) {
+    let already_varies = headers
+        .get_all(header::VARY)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|name| {
+            let name = name.trim();
+            name == "*" || name.eq_ignore_ascii_case("accept-encoding")
+        });
+    if !already_varies {
+        headers.append(header::VARY, HeaderValue::from_static("Accept-Encoding"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use async_compression::tokio::bufread::GzipDecoder;
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+    use crate::response_building::bytes_to_boxed_body;
+
+    const ALL: [ContentCoding; 4] = [
+        ContentCoding::Brotli,
+        ContentCoding::Zstd,
+        ContentCoding::Gzip,
+        ContentCoding::Deflate,
+    ];
+
+    #[test]
+    fn negotiates_by_quality_then_preference() {
+        assert_eq!(negotiate("gzip, br", &ALL), Some(ContentCoding::Brotli));
+        assert_eq!(
+            negotiate("gzip;q=1.0, br;q=0.5", &ALL),
+            Some(ContentCoding::Gzip)
+        );
+        assert_eq!(
+            negotiate("*;q=0.1, br;q=0", &ALL),
+            Some(ContentCoding::Zstd)
+        );
+        assert_eq!(negotiate("identity", &ALL), None);
+        assert_eq!(negotiate("", &ALL), None);
+        assert_eq!(negotiate("x-gzip", &ALL), Some(ContentCoding::Gzip));
+    }
+
+    #[tokio::test]
+    async fn compresses_large_json() {
+        let json = format!("[{}]", vec!["{\"state\":\"on\"}"; 500].join(","));
+        let response = Response::builder()
+            .header(header::CONTENT_TYPE, "application/json")
+            .header(header::VARY, "Origin")
+            .body(bytes_to_boxed_body(json.clone()))
+            .unwrap();
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+
+        let response = compress_response(&request_headers, response, &CompressionConfig::default());
+        assert_eq!(response.headers()[header::CONTENT_ENCODING], "gzip");
+        assert!(response.headers().get(header::CONTENT_LENGTH).is_none());
+        let vary: Vec<_> = response.headers().get_all(header::VARY).iter().collect();
+        assert_eq!(vary, ["Origin", "Accept-Encoding"]);
+
+        let compressed = response.into_body().collect().await.unwrap().to_bytes();
+        assert!(compressed.len() < json.len());
+        let mut decompressed = String::new();
+        GzipDecoder::new(&compressed[..])
+            .read_to_string(&mut decompressed)
+            .await
+            .unwrap();
+        assert_eq!(decompressed, json);
+    }
+
+    #[test]
+    fn skips_small_and_precompressed_bodies() {
+        let mut request_headers = HeaderMap::new();
+        request_headers.insert(header::ACCEPT_ENCODING, "gzip".parse().unwrap());
+        let config = CompressionConfig::default();
+
+        let small = compress_response(
+            &request_headers,
+            Response::new(bytes_to_boxed_body("Ok")),
+            &config,
+        );
+        assert!(small.headers().get(header::CONTENT_ENCODING).is_none());
+        assert_eq!(small.headers()[header::VARY], "Accept-Encoding");
+
+        let image = compress_response(
+            &request_headers,
+            Response::builder()
+                .header(header::CONTENT_TYPE, "image/png")
+                .body(bytes_to_boxed_body(vec![0u8; 4096]))
+                .unwrap(),
+            &config,
+        );
+        assert!(image.headers().get(header::CONTENT_ENCODING).is_none());
+        assert!(image.headers().get(header::VARY).is_none());
+    }
+}
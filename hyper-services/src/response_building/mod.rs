@@ -1,6 +1,11 @@
-use std::{collections::HashMap, fs::FileType, future::Future, pin::Pin};
+pub mod compression;
+pub mod security_headers;
+pub mod sse;
+pub mod streaming;
 
-use futures_util::{future::BoxFuture, TryStreamExt};
+use std::{collections::HashMap, fs::FileType, future::Future, pin::Pin, sync::Mutex, task::{Context, Poll}};
+
+use futures_util::{future::BoxFuture, Stream};
 use http_body_util::{combinators::BoxBody, BodyExt, Full};
 use hyper::{
     body::{Bytes, Frame, Incoming},
@@ -31,12 +36,31 @@ pub fn empty_body() -> HandlerBody {
     bytes_to_boxed_body("")
 }
 
+//See streaming for other sources.
 pub fn stream_to_boxed_body(stream: ReaderStream<tokio::fs::File>) -> HandlerBody {
-    let remapped_stream = stream.map_err(|e| match e {
-        e => Box::new(e) as HandlerError,
-    });
-    let stream_body = http_body_util::StreamBody::new(remapped_stream.map_ok(Frame::data));
-    stream_body.boxed()
+    streaming::byte_stream_to_boxed_body(stream)
+}
+
+//Boxes any Send stream of frames. The stream doesn't need to be Sync.
+pub fn frame_stream_to_boxed_body<S>(stream: S) -> HandlerBody
+where
+    S: Stream<Item = Result<Frame<Bytes>, HandlerError>> + Send + 'static,
+{
+    http_body_util::StreamBody::new(SyncStream(Mutex::new(Box::pin(stream)))).boxed()
+}
+
+//BoxBody requires Sync, but the stream is only ever polled through &mut, so the mutex is never contended.
+struct SyncStream<S: Stream>(Mutex<Pin<Box<S>>>);
+
+impl<S: Stream> Stream for SyncStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<S::Item>> {
+        match self.0.get_mut() {
+            Ok(stream) => stream.as_mut().poll_next(cx),
+            Err(poisoned) => poisoned.into_inner().as_mut().poll_next(cx),
+        }
+    }
 }
 
 pub fn ok() -> Response<HandlerBody> {
@@ -60,6 +84,13 @@ pub fn bad_request() -> Response<HandlerBody> {
         .expect("Should produce response.")
 }
 
+pub fn forbidden() -> Response<HandlerBody> {
+    Response::builder()
+        .status(hyper::StatusCode::FORBIDDEN)
+        .body(bytes_to_boxed_body("Forbidden."))
+        .expect("Should produce response.")
+}
+
 pub fn server_side_failure() -> Response<HandlerBody> {
     Response::builder()
         .status(hyper::StatusCode::INTERNAL_SERVER_ERROR)
@@ -68,9 +99,11 @@ pub fn server_side_failure() -> Response<HandlerBody> {
 }
 
 const SUFFIXES_TO_TRY: [&str; 3] = ["", ".html", "/index.html"];
+#[tracing::instrument(level = "debug", skip(additional_headers))]
 pub async fn send_file(file_system_root_directory: &str, request_path: &str, additional_headers:Option<hyper::HeaderMap>) -> HandlerResult {
     if request_path.contains("..") {
         //Reject attempts to access parent directories
+        tracing::warn!("Rejected request path containing a parent directory.");
         return Ok(bad_request());
     } else {
         let mut path = file_system_root_directory.to_string() + request_path; //need to prepend to get to this file system.
@@ -97,15 +130,15 @@ pub async fn send_file(file_system_root_directory: &str, request_path: &str, add
                                     "png" => "image/png",
                                     "tif" | "tiff" => "image/tiff",
                                     _ => {
-                                        eprintln!(
-                                            "Couldn't determine file type for {}",
-                                            final_path
+                                        tracing::warn!(
+                                            path = final_path,
+                                            "Couldn't determine file type."
                                         );
                                         "text/plain"
                                     }
                                 },
                                 None => {
-                                    eprintln!("Couldn't determine file type for {}", final_path);
+                                    tracing::warn!(path = final_path, "Couldn't determine file type.");
                                     "text/plain"
                                 }
                             };
@@ -136,6 +169,7 @@ pub async fn send_file(file_system_root_directory: &str, request_path: &str, add
                             }
                             
                             let response=response_builder.body(boxed_body).unwrap();
+                            tracing::debug!(path = final_path, content_type, size = meta.len(), "Sending file.");
                             return Ok(response);
                         }
                     }
@@ -144,6 +178,7 @@ pub async fn send_file(file_system_root_directory: &str, request_path: &str, add
                 Err(_) => {}
             }
         }
+        tracing::debug!("File not found.");
         return Ok(not_found());
     }
 }
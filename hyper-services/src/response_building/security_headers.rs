@@ -0,0 +1,250 @@
+use std::time::Duration;
+
+use base64::Engine;
+use hyper::{
+    header::{self, HeaderName, HeaderValue},
+    HeaderMap, Response,
+};
+
+const HEADER_PERMISSIONS_POLICY: &str = "permissions-policy";
+//Replaced with the response's nonce in the Content-Security-Policy.
+pub const NONCE_PLACEHOLDER: &str = "{nonce}";
+
+//A per-response nonce for inline scripts and styles. Handlers generate one, use it in their markup and
+//insert it into the response extensions, where add_security_headers picks it up for the policy.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CspNonce(String);
+
+impl CspNonce {
+    pub fn generate() -> CspNonce {
+        let bytes: [u8; 16] = rand::random();
+        CspNonce(base64::engine::general_purpose::STANDARD.encode(bytes))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hsts {
+    pub max_age: Duration,
+    pub include_subdomains: bool,
+    pub preload: bool,
+}
+
+impl Hsts {
+    fn header_value(&self) -> String {
+        let mut value = format!("max-age={}", self.max_age.as_secs());
+        if self.include_subdomains {
+            value.push_str("; includeSubDomains");
+        }
+        if self.preload {
+            value.push_str("; preload");
+        }
+        value
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOptions {
+    Deny,
+    SameOrigin,
+}
+
+impl FrameOptions {
+    fn x_frame_options(&self) -> &'static str {
+        match self {
+            FrameOptions::Deny => "DENY",
+            FrameOptions::SameOrigin => "SAMEORIGIN",
+        }
+    }
+
+    fn frame_ancestors(&self) -> &'static str {
+        match self {
+            FrameOptions::Deny => "frame-ancestors 'none'",
+            FrameOptions::SameOrigin => "frame-ancestors 'self'",
+        }
+    }
+}
+
+//Headers a handler already set are left alone. Browsers ignore HSTS over plain HTTP, so it's safe to send
+//behind a proxy that terminates TLS.
+#[derive(Debug, Clone, Default)]
+pub struct SecurityHeaders {
+    pub hsts: Option<Hsts>,
+    //May contain {nonce}, e.g. "script-src 'self' 'nonce-{nonce}'".
+    pub content_security_policy: Option<String>,
+    pub nosniff: bool,
+    //Also added to the policy as frame-ancestors, unless the policy has its own.
+    pub frame_options: Option<FrameOptions>,
+    pub referrer_policy: Option<String>,
+    pub permissions_policy: Option<String>,
+}
+
+impl SecurityHeaders {
+    //Nothing is added until configured.
+    pub fn new() -> SecurityHeaders {
+        SecurityHeaders::default()
+    }
+
+    //For JSON APIs: nothing may be loaded, framed or referred to.
+    pub fn strict_api() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts: Some(Hsts {
+                max_age: Duration::from_secs(63_072_000),
+                include_subdomains: true,
+                preload: false,
+            }),
+            content_security_policy: Some("default-src 'none'; base-uri 'none'; form-action 'none'".to_string()),
+            nosniff: true,
+            frame_options: Some(FrameOptions::Deny),
+            referrer_policy: Some("no-referrer".to_string()),
+            permissions_policy: Some(
+                "accelerometer=(), camera=(), geolocation=(), gyroscope=(), magnetometer=(), microphone=(), payment=(), usb=()"
+                    .to_string(),
+            ),
+        }
+    }
+
+    //For pages served from this origin, with nonces for inline scripts and styles.
+    pub fn web_app() -> SecurityHeaders {
+        SecurityHeaders {
+            hsts: Some(Hsts {
+                max_age: Duration::from_secs(31_536_000),
+                include_subdomains: false,
+                preload: false,
+            }),
+            content_security_policy: Some(
+                "default-src 'self'; script-src 'self' 'nonce-{nonce}'; style-src 'self' 'nonce-{nonce}'; img-src 'self' data:; object-src 'none'; base-uri 'self'; form-action 'self'"
+                    .to_string(),
+            ),
+            nosniff: true,
+            frame_options: Some(FrameOptions::SameOrigin),
+            referrer_policy: Some("strict-origin-when-cross-origin".to_string()),
+            permissions_policy: Some("camera=(), geolocation=(), microphone=(), payment=()".to_string()),
+        }
+    }
+
+    pub fn with_hsts(mut self, hsts: Option<Hsts>) -> SecurityHeaders {
+        self.hsts = hsts;
+        self
+    }
+
+    pub fn with_content_security_policy(mut self, policy: &str) -> SecurityHeaders {
+        self.content_security_policy = Some(policy.to_string());
+        self
+    }
+
+    pub fn with_frame_options(mut self, frame_options: Option<FrameOptions>) -> SecurityHeaders {
+        self.frame_options = frame_options;
+        self
+    }
+
+    pub fn with_referrer_policy(mut self, policy: &str) -> SecurityHeaders {
+        self.referrer_policy = Some(policy.to_string());
+        self
+    }
+
+    pub fn with_permissions_policy(mut self, policy: &str) -> SecurityHeaders {
+        self.permissions_policy = Some(policy.to_string());
+        self
+    }
+
+    //A policy with {nonce} and no nonce given gets a fresh one, so inline content stays blocked.
+    fn content_security_policy(&self, nonce: Option<&CspNonce>) -> Option<String> {
+        let mut policy = match &self.content_security_policy {
+            Some(policy) if policy.contains(NONCE_PLACEHOLDER) => {
+                let nonce = nonce.cloned().unwrap_or_else(CspNonce::generate);
+                policy.replace(NONCE_PLACEHOLDER, nonce.as_str())
+            }
+            Some(policy) => policy.clone(),
+            None => return None,
+        };
+        if let Some(frame_options) = self.frame_options {
+            if !policy.contains("frame-ancestors") {
+                policy = format!("{}; {}", policy.trim_end_matches([';', ' ']), frame_options.frame_ancestors());
+            }
+        }
+        Some(policy)
+    }
+
+    fn apply(&self, headers: &mut HeaderMap, nonce: Option<&CspNonce>) {
+        if let Some(hsts) = &self.hsts {
+            insert_missing(headers, header::STRICT_TRANSPORT_SECURITY, &hsts.header_value());
+        }
+        if let Some(policy) = self.content_security_policy(nonce) {
+            insert_missing(headers, header::CONTENT_SECURITY_POLICY, &policy);
+        }
+        if self.nosniff {
+            insert_missing(headers, header::X_CONTENT_TYPE_OPTIONS, "nosniff");
+        }
+        if let Some(frame_options) = self.frame_options {
+            insert_missing(headers, header::X_FRAME_OPTIONS, frame_options.x_frame_options());
+        }
+        if let Some(policy) = &self.referrer_policy {
+            insert_missing(headers, header::REFERRER_POLICY, policy);
+        }
+        if let Some(policy) = &self.permissions_policy {
+            insert_missing(headers, HeaderName::from_static(HEADER_PERMISSIONS_POLICY), policy);
+        }
+    }
+}
+
+fn insert_missing(headers: &mut HeaderMap, name: HeaderName, value: &str) {
+    match HeaderValue::from_str(value) {
+        Ok(value) => {
+            headers.entry(name).or_insert(value);
+        }
+        Err(_) => tracing::warn!(header = %name, "Security header value isn't valid, skipping it."),
+    }
+}
+
+//Post-processor for handler and send_file responses.
+pub fn add_security_headers<B>(mut response: Response<B>, config: &SecurityHeaders) -> Response<B> {
+    let nonce = response.extensions().get::<CspNonce>().cloned();
+    config.apply(response.headers_mut(), nonce.as_ref());
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response_building::{bytes_to_boxed_body, send_file};
+
+    #[test]
+    fn fills_in_nonce_and_frame_ancestors() {
+        let nonce = CspNonce::generate();
+        let mut response = Response::new(bytes_to_boxed_body("<script nonce=...>"));
+        response.extensions_mut().insert(nonce.clone());
+        response.headers_mut().insert(header::REFERRER_POLICY, HeaderValue::from_static("origin"));
+
+        let response = add_security_headers(response, &SecurityHeaders::web_app());
+        let headers = response.headers();
+        let policy = headers[header::CONTENT_SECURITY_POLICY].to_str().unwrap();
+        assert!(policy.contains(&format!("'nonce-{}'", nonce.as_str())));
+        assert!(!policy.contains(NONCE_PLACEHOLDER));
+        assert!(policy.ends_with("; frame-ancestors 'self'"));
+        assert_eq!(headers[header::X_FRAME_OPTIONS], "SAMEORIGIN");
+        assert_eq!(headers[header::REFERRER_POLICY], "origin");
+        assert_eq!(headers[header::STRICT_TRANSPORT_SECURITY], "max-age=31536000");
+    }
+
+    #[tokio::test]
+    async fn applies_to_send_file() {
+        let directory = std::env::temp_dir().join(format!("hyper-services-security-{}", std::process::id()));
+        std::fs::create_dir_all(&directory).unwrap();
+        std::fs::write(directory.join("index.html"), "<p>hi</p>").unwrap();
+
+        let response = send_file(directory.to_str().unwrap(), "/", None).await.unwrap();
+        let response = add_security_headers(response, &SecurityHeaders::strict_api());
+        let headers = response.headers();
+        assert_eq!(headers[header::X_CONTENT_TYPE_OPTIONS], "nosniff");
+        assert_eq!(
+            headers[header::CONTENT_SECURITY_POLICY],
+            "default-src 'none'; base-uri 'none'; form-action 'none'; frame-ancestors 'none'"
+        );
+        assert!(headers.contains_key(HEADER_PERMISSIONS_POLICY));
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
@@ -0,0 +1,347 @@
+use std::{
+    collections::VecDeque,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use futures_util::{stream, Stream, StreamExt};
+use hyper::{
+    body::{Bytes, Frame},
+    header::{self, HeaderValue},
+    HeaderMap, Response, StatusCode,
+};
+use serde::Serialize;
+use tokio::sync::{broadcast, mpsc, oneshot};
+
+use crate::{
+    commons::{HandlerBody, HandlerError},
+    response_building::frame_stream_to_boxed_body,
+};
+
+pub const HEADER_LAST_EVENT_ID: &str = "Last-Event-ID";
+pub const CONTENT_TYPE_EVENT_STREAM: &str = "text/event-stream";
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+const KEEP_ALIVE_COMMENT: &[u8] = b":\n\n";
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SseEvent {
+    pub id: Option<String>,
+    pub event: Option<String>,
+    pub data: String,
+    pub retry: Option<Duration>,
+}
+
+impl SseEvent {
+    pub fn new<S: Into<String>>(data: S) -> SseEvent {
+        SseEvent {
+            data: data.into(),
+            ..Default::default()
+        }
+    }
+
+    pub fn json<T: Serialize>(value: &T) -> Result<SseEvent, serde_json::Error> {
+        Ok(SseEvent::new(serde_json::to_string(value)?))
+    }
+
+    pub fn with_id<S: Into<String>>(mut self, id: S) -> SseEvent {
+        self.id = Some(id.into());
+        self
+    }
+
+    pub fn with_event<S: Into<String>>(mut self, event: S) -> SseEvent {
+        self.event = Some(event.into());
+        self
+    }
+
+    pub fn with_retry(mut self, retry: Duration) -> SseEvent {
+        self.retry = Some(retry);
+        self
+    }
+
+    //Wire format. Multi-line data is split over several data fields, line breaks in other fields are dropped.
+    //CRLF, CR and LF all end a line for clients, so data is split on each of them.
+    pub fn to_bytes(&self) -> Bytes {
+        let single_line = |value: &str| value.replace(['\r', '\n', '\0'], "");
+
+        let mut text = String::new();
+        if let Some(event) = &self.event {
+            text.push_str("event: ");
+            text.push_str(&single_line(event));
+            text.push('\n');
+        }
+        if let Some(id) = &self.id {
+            text.push_str("id: ");
+            text.push_str(&single_line(id));
+            text.push('\n');
+        }
+        if let Some(retry) = &self.retry {
+            text.push_str(&format!("retry: {}\n", retry.as_millis()));
+        }
+        for line in self.data.replace("\r\n", "\n").split(['\r', '\n']) {
+            text.push_str("data: ");
+            text.push_str(line);
+            text.push('\n');
+        }
+        text.push('\n');
+        Bytes::from(text)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct SseOptions {
+    //A comment is sent this often so proxies don't close idle streams. None disables it.
+    pub keep_alive: Option<Duration>,
+}
+
+impl Default for SseOptions {
+    fn default() -> Self {
+        SseOptions {
+            keep_alive: Some(DEFAULT_KEEP_ALIVE),
+        }
+    }
+}
+
+//Resolves once the client has gone away and the response body was dropped.
+pub struct SseDisconnected(oneshot::Receiver<()>);
+
+impl Future for SseDisconnected {
+    type Output = ();
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<()> {
+        Pin::new(&mut self.0).poll(cx).map(|_| ())
+    }
+}
+
+//Turns a stream of events into a text/event-stream response.
+pub fn sse_response<S>(events: S, options: SseOptions) -> (Response<HandlerBody>, SseDisconnected)
+where
+    S: Stream<Item = SseEvent> + Send + 'static,
+{
+    let (disconnect_guard, disconnected) = oneshot::channel::<()>();
+    let keep_alive = options.keep_alive.map(|period| {
+        let mut interval = tokio::time::interval_at(tokio::time::Instant::now() + period, period);
+        interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        interval
+    });
+
+    let frames = stream::unfold(
+        (Box::pin(events), keep_alive, disconnect_guard),
+        |(mut events, mut keep_alive, disconnect_guard)| async move {
+            let bytes = match &mut keep_alive {
+                Some(interval) => tokio::select! {
+                    event = events.next() => event.map(|event| event.to_bytes()),
+                    _ = interval.tick() => Some(Bytes::from_static(KEEP_ALIVE_COMMENT)),
+                },
+                None => events.next().await.map(|event| event.to_bytes()),
+            };
+            bytes.map(|bytes| {
+                (
+                    Ok::<_, HandlerError>(Frame::data(bytes)),
+                    (events, keep_alive, disconnect_guard),
+                )
+            })
+        },
+    );
+
+    let response = Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, CONTENT_TYPE_EVENT_STREAM)
+        .header(header::CACHE_CONTROL, "no-cache")
+        //Stops nginx from buffering the stream.
+        .header("X-Accel-Buffering", "no")
+        .body(frame_stream_to_boxed_body(frames))
+        .expect("Should produce response.");
+
+    (response, SseDisconnected(disconnected))
+}
+
+//Sends events into an open event stream.
+#[derive(Clone)]
+pub struct SseSender(mpsc::Sender<SseEvent>);
+
+impl SseSender {
+    //Fails once the client has disconnected.
+    pub async fn send(&self, event: SseEvent) -> Result<(), HandlerError> {
+        self.0
+            .send(event)
+            .await
+            .map_err(|_| "Event stream client disconnected.".into())
+    }
+
+    pub fn is_closed(&self) -> bool {
+        self.0.is_closed()
+    }
+
+    //Resolves once the client has disconnected.
+    pub async fn closed(&self) {
+        self.0.closed().await
+    }
+}
+
+pub fn sse_channel(buffer: usize, options: SseOptions) -> (SseSender, Response<HandlerBody>) {
+    let (sender, mut receiver) = mpsc::channel(buffer);
+    let events = stream::poll_fn(move |cx| receiver.poll_recv(cx));
+    let (response, _) = sse_response(events, options);
+    (SseSender(sender), response)
+}
+
+pub fn last_event_id(headers: &HeaderMap) -> Option<&str> {
+    headers
+        .get(HEADER_LAST_EVENT_ID)
+        .and_then(|value: &HeaderValue| value.to_str().ok())
+}
+
+struct ReplayState {
+    events: VecDeque<SseEvent>,
+    next_id: u64,
+}
+
+//Keeps the most recent events so reconnecting clients can resume from Last-Event-ID, and fans new events out to subscribers.
+#[derive(Clone)]
+pub struct ReplayBuffer {
+    capacity: usize,
+    state: Arc<Mutex<ReplayState>>,
+    live: broadcast::Sender<SseEvent>,
+}
+
+impl ReplayBuffer {
+    pub fn new(capacity: usize) -> ReplayBuffer {
+        let (live, _) = broadcast::channel(capacity.max(1));
+        ReplayBuffer {
+            capacity,
+            state: Arc::new(Mutex::new(ReplayState {
+                events: VecDeque::with_capacity(capacity),
+                next_id: 1,
+            })),
+            live,
+        }
+    }
+
+    //Events without an id get a sequential one. Returns the event as stored.
+    pub fn push(&self, event: SseEvent) -> SseEvent {
+        let mut state = self.state.lock().expect("Replay buffer lock poisoned.");
+        let event = match event.id {
+            Some(_) => event,
+            None => {
+                let id = state.next_id.to_string();
+                state.next_id += 1;
+                event.with_id(id)
+            }
+        };
+        if self.capacity > 0 {
+            if state.events.len() == self.capacity {
+                state.events.pop_front();
+            }
+            state.events.push_back(event.clone());
+        }
+        //Sent while locked so subscribe can't miss or repeat an event.
+        let _ = self.live.send(event.clone());
+        event
+    }
+
+    //Events after last_event_id. Everything buffered if the id is unknown or was already evicted.
+    pub fn since(&self, last_event_id: Option<&str>) -> Vec<SseEvent> {
+        let state = self.state.lock().expect("Replay buffer lock poisoned.");
+        Self::since_locked(&state, last_event_id)
+    }
+
+    fn since_locked(state: &ReplayState, last_event_id: Option<&str>) -> Vec<SseEvent> {
+        let last_event_id = match last_event_id {
+            Some(last_event_id) => last_event_id,
+            None => return Vec::new(),
+        };
+        match state
+            .events
+            .iter()
+            .position(|event| event.id.as_deref() == Some(last_event_id))
+        {
+            Some(position) => state.events.iter().skip(position + 1).cloned().collect(),
+            None => state.events.iter().cloned().collect(),
+        }
+    }
+
+    //Missed events followed by live ones. Slow subscribers that fall behind skip the events they lost.
+    pub fn subscribe(&self, last_event_id: Option<&str>) -> impl Stream<Item = SseEvent> + Send + 'static {
+        let state = self.state.lock().expect("Replay buffer lock poisoned.");
+        let missed = Self::since_locked(&state, last_event_id);
+        let receiver = self.live.subscribe();
+        drop(state);
+
+        let live = stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(event) => return Some((event, receiver)),
+                    Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                        tracing::warn!(skipped, "Event stream subscriber fell behind.");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => return None,
+                }
+            }
+        });
+        stream::iter(missed).chain(live)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn formats_events() {
+        let event = SseEvent::new("line one\nline two")
+            .with_id("7")
+            .with_event("state")
+            .with_retry(Duration::from_secs(3));
+        assert_eq!(
+            event.to_bytes(),
+            "event: state\nid: 7\nretry: 3000\ndata: line one\ndata: line two\n\n"
+        );
+    }
+
+    #[test]
+    fn splits_data_on_every_line_break() {
+        let event = SseEvent::new("a\r\nb\rid: x\nc\revent: y\r\rd");
+        assert_eq!(
+            event.to_bytes(),
+            "data: a\ndata: b\ndata: id: x\ndata: c\ndata: event: y\ndata: \ndata: d\n\n"
+        );
+    }
+
+    #[test]
+    fn keeps_id_and_event_on_one_line() {
+        let event = SseEvent::new("x").with_id("1\r\ndata: injected").with_event("a\rretry: 1\nb");
+        assert_eq!(event.to_bytes(), "event: aretry: 1b\nid: 1data: injected\ndata: x\n\n");
+    }
+
+    #[test]
+    fn replays_events_after_last_id() {
+        let buffer = ReplayBuffer::new(3);
+        for state in ["a", "b", "c", "d"] {
+            buffer.push(SseEvent::new(state));
+        }
+
+        let data = |events: Vec<SseEvent>| -> Vec<String> {
+            events.into_iter().map(|event| event.data).collect()
+        };
+        assert_eq!(data(buffer.since(Some("3"))), ["d"]);
+        assert_eq!(data(buffer.since(Some("1"))), ["b", "c", "d"]);
+        assert!(buffer.since(None).is_empty());
+    }
+
+    #[tokio::test]
+    async fn subscribers_get_missed_then_live_events() {
+        let buffer = ReplayBuffer::new(10);
+        buffer.push(SseEvent::new("a"));
+        buffer.push(SseEvent::new("b"));
+
+        let mut events = Box::pin(buffer.subscribe(Some("1")));
+        buffer.push(SseEvent::new("c"));
+
+        assert_eq!(events.next().await.unwrap().data, "b");
+        assert_eq!(events.next().await.unwrap().data, "c");
+    }
+}
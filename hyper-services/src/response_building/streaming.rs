@@ -0,0 +1,255 @@
+use std::future::Future;
+
+use futures_util::{stream, Stream, StreamExt, TryStreamExt};
+use http_body_util::{channel::Channel, BodyExt};
+use hyper::{
+    body::{Bytes, Frame},
+    HeaderMap,
+};
+use serde::Serialize;
+use tokio::{io::AsyncRead, process::Child, sync::mpsc};
+use tokio_util::io::ReaderStream;
+
+use crate::{
+    commons::{HandlerBody, HandlerError},
+    response_building::frame_stream_to_boxed_body,
+};
+
+pub const CONTENT_TYPE_NDJSON: &str = "application/x-ndjson";
+pub const CONTENT_TYPE_JSON: &str = "application/json";
+
+//Serialized items are gathered into frames of about this size.
+const SERIALIZED_FRAME_SIZE: usize = 16 * 1024;
+
+pub type BodySender = http_body_util::channel::Sender<Bytes, HandlerError>;
+
+pub fn async_read_to_boxed_body<R>(reader: R) -> HandlerBody
+where
+    R: AsyncRead + Send + 'static,
+{
+    byte_stream_to_boxed_body(ReaderStream::new(reader))
+}
+
+pub fn byte_stream_to_boxed_body<S, B, E>(stream: S) -> HandlerBody
+where
+    S: Stream<Item = Result<B, E>> + Send + 'static,
+    B: Into<Bytes>,
+    E: Into<HandlerError>,
+{
+    frame_stream_to_boxed_body(
+        stream
+            .map_ok(|chunk| Frame::data(chunk.into()))
+            .map_err(|e| e.into()),
+    )
+}
+
+//Ends when every sender has been dropped.
+pub fn receiver_to_boxed_body<B>(mut receiver: mpsc::Receiver<B>) -> HandlerBody
+where
+    B: Into<Bytes> + Send + 'static,
+{
+    let chunks = stream::poll_fn(move |cx| receiver.poll_recv(cx));
+    frame_stream_to_boxed_body(chunks.map(|chunk| Ok(Frame::data(chunk.into()))))
+}
+
+//The sender can send data, finish with trailers, or abort the body with an error.
+pub fn body_channel(buffer: usize) -> (BodySender, HandlerBody) {
+    let (sender, body) = Channel::<Bytes, HandlerError>::new(buffer);
+    (sender, body.boxed())
+}
+
+//Trailers are only sent if the client announced support with "TE: trailers".
+pub fn with_trailers<F>(body: HandlerBody, trailers: F) -> HandlerBody
+where
+    F: Future<Output = Option<Result<HeaderMap, HandlerError>>> + Send + Sync + 'static,
+{
+    body.with_trailers(trailers).boxed()
+}
+
+//Streams stdout of a child spawned with Stdio::piped(). A failing exit status aborts the body, and the child is killed if the client disconnects.
+pub fn child_stdout_to_boxed_body(mut child: Child) -> Result<HandlerBody, HandlerError> {
+    let stdout = match child.stdout.take() {
+        Some(stdout) => stdout,
+        None => return Err("Child process stdout isn't piped.".into()),
+    };
+
+    let output = ReaderStream::new(stdout).map(|chunk| match chunk {
+        Ok(chunk) => Ok(Frame::data(chunk)),
+        Err(e) => Err(Box::new(e) as HandlerError),
+    });
+    let mut guard = ChildGuard(Some(child));
+    let exit = stream::once(async move {
+        let status = match guard.0.as_mut() {
+            Some(child) => child.wait().await,
+            None => return None,
+        };
+        guard.disarm();
+        match status {
+            Ok(status) if status.success() => None,
+            Ok(status) => Some(Err(format!("Child process exited with {}.", status).into())),
+            Err(e) => Some(Err(Box::new(e) as HandlerError)),
+        }
+    })
+    .filter_map(|result| async move { result });
+
+    Ok(frame_stream_to_boxed_body(output.chain(exit)))
+}
+
+struct ChildGuard(Option<Child>);
+
+impl ChildGuard {
+    //The child already exited, nothing left to kill.
+    fn disarm(&mut self) {
+        self.0 = None;
+    }
+}
+
+impl Drop for ChildGuard {
+    fn drop(&mut self) {
+        if let Some(child) = &mut self.0 {
+            if let Err(e) = child.start_kill() {
+                tracing::debug!(error = %e, "Couldn't kill child process.");
+            }
+        }
+    }
+}
+
+//One JSON document per line, serialized lazily as the body is sent.
+pub fn ndjson_body<I, T>(items: I) -> HandlerBody
+where
+    I: IntoIterator<Item = T>,
+    I::IntoIter: Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    serialized_body(items.into_iter(), b"", b"", b"\n", b"")
+}
+
+//A single JSON array, serialized lazily as the body is sent.
+pub fn json_array_body<I, T>(items: I) -> HandlerBody
+where
+    I: IntoIterator<Item = T>,
+    I::IntoIter: Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    serialized_body(items.into_iter(), b"[", b",", b"", b"]")
+}
+
+//NDJSON from an async source, one frame per item.
+pub fn ndjson_stream_body<S, T>(items: S) -> HandlerBody
+where
+    S: Stream<Item = T> + Send + 'static,
+    T: Serialize,
+{
+    frame_stream_to_boxed_body(items.map(|item| {
+        let mut line = serde_json::to_vec(&item)?;
+        line.push(b'\n');
+        Ok(Frame::data(Bytes::from(line)))
+    }))
+}
+
+fn serialized_body<I, T>(
+    items: I,
+    open: &'static [u8],
+    separator: &'static [u8],
+    terminator: &'static [u8],
+    close: &'static [u8],
+) -> HandlerBody
+where
+    I: Iterator<Item = T> + Send + 'static,
+    T: Serialize + Send + 'static,
+{
+    let mut items = items.peekable();
+    let mut opened = false;
+    let mut closed = false;
+    let frames = std::iter::from_fn(move || {
+        if closed {
+            return None;
+        }
+        let mut buffer = Vec::with_capacity(SERIALIZED_FRAME_SIZE);
+        if !opened {
+            buffer.extend_from_slice(open);
+            opened = true;
+        }
+        while buffer.len() < SERIALIZED_FRAME_SIZE {
+            match items.next() {
+                Some(item) => {
+                    if let Err(e) = serde_json::to_writer(&mut buffer, &item) {
+                        closed = true;
+                        return Some(Err(Box::new(e) as HandlerError));
+                    }
+                    buffer.extend_from_slice(terminator);
+                    if items.peek().is_some() {
+                        buffer.extend_from_slice(separator);
+                    }
+                }
+                None => {
+                    buffer.extend_from_slice(close);
+                    closed = true;
+                    break;
+                }
+            }
+        }
+        Some(Ok(Frame::data(Bytes::from(buffer))))
+    });
+    frame_stream_to_boxed_body(stream::iter(frames))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn collect(body: HandlerBody) -> String {
+        String::from_utf8(body.collect().await.unwrap().to_bytes().to_vec()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn serializes_iterators() {
+        assert_eq!(collect(json_array_body(vec![1, 2, 3])).await, "[1,2,3]");
+        assert_eq!(collect(json_array_body(Vec::<u8>::new())).await, "[]");
+        assert_eq!(
+            collect(ndjson_body(vec!["a", "b"])).await,
+            "\"a\"\n\"b\"\n"
+        );
+
+        let large = collect(json_array_body(0..10_000)).await;
+        let parsed: Vec<u32> = serde_json::from_str(&large).unwrap();
+        assert_eq!(parsed.len(), 10_000);
+    }
+
+    #[tokio::test]
+    async fn streams_channels_with_trailers() {
+        let (mut sender, body) = body_channel(4);
+        tokio::spawn(async move {
+            sender.send_data(Bytes::from("a,b\n")).await.unwrap();
+            let mut trailers = HeaderMap::new();
+            trailers.insert("x-rows", "1".parse().unwrap());
+            sender.send_trailers(trailers).await.unwrap();
+        });
+        let collected = body.collect().await.unwrap();
+        assert_eq!(collected.trailers().unwrap()["x-rows"], "1");
+        assert_eq!(collected.to_bytes(), "a,b\n");
+    }
+
+    #[tokio::test]
+    async fn streams_child_output() {
+        let child = tokio::process::Command::new("echo")
+            .arg("hello")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert_eq!(
+            collect(child_stdout_to_boxed_body(child).unwrap()).await,
+            "hello\n"
+        );
+
+        let failing = tokio::process::Command::new("false")
+            .stdout(std::process::Stdio::piped())
+            .spawn()
+            .unwrap();
+        assert!(child_stdout_to_boxed_body(failing)
+            .unwrap()
+            .collect()
+            .await
+            .is_err());
+    }
+}
@@ -0,0 +1,266 @@
+use std::{io::Write, net::IpAddr, path::Path, time::Duration};
+
+use hyper::Request;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing_appender::{
+    non_blocking::{NonBlocking, WorkerGuard},
+    rolling::{InitError, RollingFileAppender},
+};
+
+pub use tracing_appender::rolling::Rotation;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    //NCSA Common Log Format
+    Common,
+    //Common plus referer and user agent
+    Combined,
+    //One JSON object per line
+    JsonLines,
+}
+
+//Lines go through a channel to a dedicated writer thread, so requests never wait on the disk.
+//If the writer falls behind, lines are dropped rather than stalling the server.
+pub struct AccessLog {
+    format: AccessLogFormat,
+    writer: NonBlocking,
+    //Flushes the pending lines when the log is dropped.
+    _guard: WorkerGuard,
+}
+
+impl AccessLog {
+    //Writes to <directory>/<file_name_prefix>.<date>.log, rotating per the given period and keeping at most max_files old files.
+    pub fn new<P: AsRef<Path>>(
+        directory: P,
+        file_name_prefix: &str,
+        format: AccessLogFormat,
+        rotation: Rotation,
+        max_files: Option<usize>,
+    ) -> Result<AccessLog, InitError> {
+        let mut builder = RollingFileAppender::builder()
+            .rotation(rotation)
+            .filename_prefix(file_name_prefix)
+            .filename_suffix("log");
+        if let Some(max_files) = max_files {
+            builder = builder.max_log_files(max_files);
+        }
+
+        let (writer, guard) = tracing_appender::non_blocking(builder.build(directory)?);
+        Ok(AccessLog {
+            format,
+            writer,
+            _guard: guard,
+        })
+    }
+
+    pub(crate) fn record(&self, entry: &AccessLogEntry) {
+        let mut line = entry.line(self.format);
+        line.push('\n');
+
+        //Clones share the channel to the writer thread.
+        if let Err(e) = self.writer.clone().write_all(line.as_bytes()) {
+            tracing::warn!(error = %e, "Couldn't write access log entry.");
+        }
+    }
+}
+
+pub(crate) struct AccessLogEntry {
+    time: OffsetDateTime,
+    client: Option<IpAddr>,
+    method: String,
+    target: String,
+    version: String,
+    referer: Option<String>,
+    user_agent: Option<String>,
+    request_id: String,
+    pub(crate) status: u16,
+    pub(crate) bytes: u64,
+    pub(crate) latency: Duration,
+}
+
+#[derive(Serialize)]
+struct JsonEntry<'a> {
+    time: String,
+    client: Option<String>,
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    status: u16,
+    bytes: u64,
+    latency_ms: f64,
+    referer: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    request_id: &'a str,
+}
+
+impl AccessLogEntry {
+    pub(crate) fn from_request<B>(
+        request: &Request<B>,
+        client: Option<IpAddr>,
+        request_id: &str,
+    ) -> AccessLogEntry {
+        let header = |name: hyper::header::HeaderName| {
+            request
+                .headers()
+                .get(name)
+                .and_then(|value| value.to_str().ok())
+                .map(|value| value.to_string())
+        };
+
+        AccessLogEntry {
+            time: OffsetDateTime::now_utc(),
+            client,
+            method: request.method().to_string(),
+            target: request
+                .uri()
+                .path_and_query()
+                .map(|target| target.to_string())
+                .unwrap_or_else(|| "/".to_string()),
+            version: format!("{:?}", request.version()),
+            referer: header(hyper::header::REFERER),
+            user_agent: header(hyper::header::USER_AGENT),
+            request_id: request_id.to_string(),
+            status: 0,
+            bytes: 0,
+            latency: Duration::ZERO,
+        }
+    }
+
+    fn line(&self, format: AccessLogFormat) -> String {
+        match format {
+            AccessLogFormat::Common => self.common(),
+            AccessLogFormat::Combined => self.combined(),
+            AccessLogFormat::JsonLines => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let client = match self.client {
+            Some(client) => client.to_string(),
+            None => "-".to_string(),
+        };
+        let bytes = match self.bytes {
+            0 => "-".to_string(),
+            bytes => bytes.to_string(),
+        };
+        format!(
+            "{} - - [{}] \"{} {} {}\" {} {}",
+            client,
+            clf_time(self.time),
+            self.method,
+            escape(&self.target),
+            self.version,
+            self.status,
+            bytes
+        )
+    }
+
+    fn combined(&self) -> String {
+        let quoted = |value: &Option<String>| match value {
+            Some(value) => format!("\"{}\"", escape(value)),
+            None => "\"-\"".to_string(),
+        };
+        format!(
+            "{} {} {}",
+            self.common(),
+            quoted(&self.referer),
+            quoted(&self.user_agent)
+        )
+    }
+
+    fn json(&self) -> String {
+        let entry = JsonEntry {
+            time: self.time.format(&Rfc3339).unwrap_or_default(),
+            client: self.client.map(|client| client.to_string()),
+            method: &self.method,
+            target: &self.target,
+            version: &self.version,
+            status: self.status,
+            bytes: self.bytes,
+            latency_ms: self.latency.as_secs_f64() * 1000.0,
+            referer: self.referer.as_deref(),
+            user_agent: self.user_agent.as_deref(),
+            request_id: &self.request_id,
+        };
+        serde_json::to_string(&entry).expect("Should serialize.")
+    }
+}
+
+//10/Oct/2000:13:55:36 +0000
+fn clf_time(time: OffsetDateTime) -> String {
+    let month = time.month().to_string();
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        time.day(),
+        &month[..3],
+        time.year(),
+        time.hour(),
+        time.minute(),
+        time.second()
+    )
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::header;
+
+    use super::*;
+
+    fn entry() -> AccessLogEntry {
+        let request = Request::builder()
+            .method("GET")
+            .uri("/search?q=rust")
+            .header(header::REFERER, "https://example.com/")
+            .header(header::USER_AGENT, "curl/8.5.0 \"beta\"")
+            .body(())
+            .unwrap();
+        let mut entry = AccessLogEntry::from_request(&request, Some("192.0.2.7".parse().unwrap()), "req-1");
+        entry.time = OffsetDateTime::from_unix_timestamp(971_186_136).unwrap();
+        entry.status = 200;
+        entry.bytes = 2326;
+        entry.latency = Duration::from_millis(12);
+        entry
+    }
+
+    #[test]
+    fn formats_common_lines() {
+        assert_eq!(
+            entry().line(AccessLogFormat::Common),
+            r#"192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=rust HTTP/1.1" 200 2326"#
+        );
+
+        let mut empty = entry();
+        empty.client = None;
+        empty.bytes = 0;
+        assert_eq!(
+            empty.line(AccessLogFormat::Common),
+            r#"- - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=rust HTTP/1.1" 200 -"#
+        );
+    }
+
+    #[test]
+    fn formats_combined_lines() {
+        assert_eq!(
+            entry().line(AccessLogFormat::Combined),
+            r#"192.0.2.7 - - [10/Oct/2000:13:55:36 +0000] "GET /search?q=rust HTTP/1.1" 200 2326 "https://example.com/" "curl/8.5.0 \"beta\"""#
+        );
+
+        let mut anonymous = entry();
+        anonymous.referer = None;
+        anonymous.user_agent = None;
+        assert!(anonymous.line(AccessLogFormat::Combined).ends_with(r#" 200 2326 "-" "-""#));
+    }
+
+    #[test]
+    fn formats_json_lines() {
+        assert_eq!(
+            entry().line(AccessLogFormat::JsonLines),
+            r#"{"time":"2000-10-10T13:55:36Z","client":"192.0.2.7","method":"GET","target":"/search?q=rust","version":"HTTP/1.1","status":200,"bytes":2326,"latency_ms":12.0,"referer":"https://example.com/","user_agent":"curl/8.5.0 \"beta\"","request_id":"req-1"}"#
+        );
+    }
+}
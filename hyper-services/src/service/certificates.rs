@@ -1,4 +1,8 @@
-use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use std::sync::Arc;
+
+use rustls::{pki_types::{CertificateDer, PrivateKeyDer}, server::{ClientHello, ResolvesServerCert}, sign::CertifiedKey, ServerConfig};
+
+use crate::{commons::HandlerError, vhost::HostTable};
 
 pub struct TlsCerts
 {
@@ -21,8 +25,70 @@ pub fn generate_simple_certificates<S:Into<Vec<String>>>(hostnames:S)->Result<Tl
             })
         },
         Err(e)=>{
-            eprintln!("Couldn't create certificates. {:?}",e);
+            tracing::error!(error = %e, "Couldn't create certificates.");
             Err(Box::new(e))
         }
     }
-}
\ No newline at end of file
+}
+
+//Picks the certificate by the server name the client sends (SNI), with the same patterns as vhost::VirtualHosts.
+#[derive(Debug, Clone, Default)]
+pub struct SniResolver
+{
+    certificates:HostTable<Arc<CertifiedKey>>
+}
+
+impl SniResolver
+{
+    pub fn new()->SniResolver
+    {
+        SniResolver::default()
+    }
+
+    pub fn with_host(mut self, pattern:&str, certs:TlsCerts)->Result<SniResolver, HandlerError>
+    {
+        self.certificates.insert(pattern, certified_key(certs)?)?;
+        Ok(self)
+    }
+
+    //Used for clients that send no server name or one without a certificate.
+    pub fn with_default(mut self, certs:TlsCerts)->Result<SniResolver, HandlerError>
+    {
+        self.certificates.set_default(certified_key(certs)?);
+        Ok(self)
+    }
+}
+
+impl ResolvesServerCert for SniResolver
+{
+    fn resolve(&self, client_hello:ClientHello<'_>)->Option<Arc<CertifiedKey>>
+    {
+        let certified = self.certificates.get(client_hello.server_name()).cloned();
+        if certified.is_none()
+        {
+            tracing::debug!(server_name = ?client_hello.server_name(), "No certificate for server name.");
+        }
+        certified
+    }
+}
+
+//Loads the key with the provider the server's config will use: the process default, which rustls installs from its
+//crate features if the application didn't install one.
+fn certified_key(certs:TlsCerts)->Result<Arc<CertifiedKey>, HandlerError>
+{
+    let provider = ServerConfig::builder().crypto_provider().clone();
+    Ok(Arc::new(CertifiedKey::from_der(certs.certs, certs.keys, &provider)?))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loads_certificates_for_valid_patterns_only() {
+        let certs = || generate_simple_certificates(vec!["a.example.com".to_string()]).unwrap();
+        assert!(SniResolver::new().with_host("*.example.com", certs()).is_ok());
+        assert!(SniResolver::new().with_host("*example.com", certs()).is_err());
+        assert!(SniResolver::new().with_default(certs()).is_ok());
+    }
+}
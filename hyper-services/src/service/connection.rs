@@ -0,0 +1,463 @@
+use std::{
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::{Duration, Instant},
+};
+
+use http_body_util::BodyExt;
+use hyper::{
+    body::{Body, Bytes, Frame, Incoming, SizeHint},
+    header::HeaderValue,
+    service::Service,
+    Request, Response, StatusCode,
+};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tracing::Instrument;
+
+use crate::{
+    access_control::AccessControl,
+    commons::{HandlerBody, HandlerError, HandlerFuture},
+    problem::Problem,
+    request_id::{RequestId, HEADER_X_REQUEST_ID},
+    request_processing::client_address::{resolve_client_addr, ClientAddr, TrustedProxies},
+    response_building::forbidden,
+    service::{
+        access_log::{AccessLog, AccessLogEntry},
+        metrics::{route_label, Metrics},
+        spawn::ConnectionProperties,
+    },
+};
+
+//Inserted into the request extensions of every request.
+//Unix socket connections have no local address, and a peer address only if ConnectionProperties::unix_peer_addr is set.
+#[derive(Debug, Clone)]
+pub struct ConnectionInfo {
+    pub peer_addr: Option<SocketAddr>,
+    pub local_addr: Option<SocketAddr>,
+    pub tls: bool,
+    pub unix_peer: Option<UnixPeerCredentials>,
+    //Whether the peer is one of ConnectionProperties::trusted_proxies, so its forwarding headers are believed.
+    pub peer_is_trusted_proxy: bool,
+}
+
+//Credentials of the process on the other end of a Unix socket. Also inserted as a request extension of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct UnixPeerCredentials {
+    pub uid: u32,
+    pub gid: u32,
+    pub pid: Option<i32>,
+}
+
+//Settings shared by every connection of one server.
+pub(crate) struct ServerContext {
+    pub(crate) access_control: Option<AccessControl>,
+    pub(crate) trusted_proxies: Option<TrustedProxies>,
+    pub(crate) access_log: Option<AccessLog>,
+    pub(crate) metrics: Option<Arc<Metrics>>,
+    pub(crate) with_upgrades: bool,
+    pub(crate) tls_handshake_timeout: Option<Duration>,
+    pub(crate) header_read_timeout: Option<Duration>,
+    pub(crate) keep_alive: bool,
+    pub(crate) idle_timeout: Option<Duration>,
+    pub(crate) max_header_size: Option<usize>,
+    pub(crate) max_headers: Option<usize>,
+    pub(crate) max_request_duration: Option<Duration>,
+    pub(crate) half_close: bool,
+    pub(crate) title_case_headers: bool,
+}
+
+impl ServerContext {
+    //TLS is set up by the listener, so it is ignored here.
+    pub(crate) fn from_properties(props: ConnectionProperties) -> ServerContext {
+        ServerContext {
+            access_control: props.access_control,
+            trusted_proxies: props.trusted_proxies,
+            access_log: props.access_log,
+            metrics: props.metrics,
+            with_upgrades: props.with_upgrades,
+            tls_handshake_timeout: props.tls_handshake_timeout,
+            header_read_timeout: props.header_read_timeout,
+            keep_alive: props.keep_alive,
+            idle_timeout: props.idle_timeout,
+            max_header_size: props.max_header_size,
+            max_headers: props.max_headers,
+            max_request_duration: props.max_request_duration,
+            half_close: props.half_close,
+            title_case_headers: props.title_case_headers,
+        }
+    }
+
+    pub(crate) fn trusts(&self, peer: Option<IpAddr>) -> bool {
+        match (peer, &self.trusted_proxies) {
+            (Some(peer), Some(trusted_proxies)) => trusted_proxies.contains(peer),
+            _ => false,
+        }
+    }
+
+    //None if the peer is denied. Otherwise whether the access check is deferred to each request,
+    //which happens for trusted proxies so the forwarded client address is checked instead.
+    //Peers without an address are only admitted without access control.
+    pub(crate) fn admit(&self, peer: Option<IpAddr>) -> Option<bool> {
+        let peer = match (peer, &self.access_control) {
+            (Some(peer), _) => peer,
+            (None, Some(_)) => return None,
+            (None, None) => return Some(false),
+        };
+        let from_trusted_proxy = self.trusts(Some(peer));
+        match &self.access_control {
+            Some(_) if from_trusted_proxy => Some(true),
+            Some(access_control) => match access_control.permits(peer) {
+                true => Some(false),
+                false => None,
+            },
+            None => Some(false),
+        }
+    }
+}
+
+//Wraps the application service for a single accepted connection.
+#[derive(Clone)]
+pub(crate) struct ConnectionService<S> {
+    inner: S,
+    info: ConnectionInfo,
+    context: Arc<ServerContext>,
+    //Set when the peer is a trusted proxy, so the access decision waits for the forwarded client address.
+    deferred_access_check: bool,
+}
+
+impl<S> ConnectionService<S> {
+    pub(crate) fn new(
+        inner: S,
+        info: ConnectionInfo,
+        context: Arc<ServerContext>,
+        deferred_access_check: bool,
+    ) -> ConnectionService<S> {
+        ConnectionService {
+            inner,
+            info,
+            context,
+            deferred_access_check,
+        }
+    }
+}
+
+impl<S> Service<Request<Incoming>> for ConnectionService<S>
+where
+    S: Service<
+        Request<Incoming>,
+        Response = Response<HandlerBody>,
+        Error = HandlerError,
+        Future = HandlerFuture,
+    >,
+{
+    type Response = Response<HandlerBody>;
+    type Error = HandlerError;
+    type Future = HandlerFuture;
+
+    fn call(&self, mut request: Request<Incoming>) -> Self::Future {
+        let started = Instant::now();
+        let client_addr = self
+            .info
+            .peer_addr
+            .map(|peer| resolve_client_addr(request.headers(), peer.ip(), self.context.trusted_proxies.as_ref()));
+        let request_id = RequestId::from_headers(request.headers());
+        let request_id_header = HeaderValue::from_str(request_id.as_str()).ok();
+
+        let span = tracing::info_span!(
+            "request",
+            method = %request.method(),
+            path = %request.uri().path(),
+            peer = %peer_label(client_addr),
+            request_id = %request_id,
+            trace_id = %request_id.trace_context().trace_id,
+            status = tracing::field::Empty,
+            latency_ms = tracing::field::Empty,
+        );
+
+        let access_log_entry = self
+            .context
+            .access_log
+            .as_ref()
+            .map(|_| AccessLogEntry::from_request(&request, client_addr, request_id.as_str()));
+
+        if let (true, Some(access_control)) =
+            (self.deferred_access_check, &self.context.access_control)
+        {
+            if !client_addr.is_some_and(|client| access_control.permits(client)) {
+                span.in_scope(|| {
+                    tracing::warn!(proxy = %peer_label(self.info.peer_addr), "Rejected request from denied client.")
+                });
+                let response = echo_request_id(forbidden(), request_id_header);
+                if let (Some(mut entry), Some(access_log)) = (access_log_entry, &self.context.access_log) {
+                    entry.status = response.status().as_u16();
+                    entry.bytes = response.body().size_hint().exact().unwrap_or(0);
+                    entry.latency = started.elapsed();
+                    access_log.record(&entry);
+                }
+                return Box::pin(async move { Ok(response) });
+            }
+        }
+
+        let method = request.method().clone();
+
+        request.extensions_mut().insert(self.info.clone());
+        if let Some(unix_peer) = self.info.unix_peer {
+            request.extensions_mut().insert(unix_peer);
+        }
+        if let Some(client_addr) = client_addr {
+            request.extensions_mut().insert(ClientAddr(client_addr));
+        }
+        request.extensions_mut().insert(request_id);
+        let future = span.in_scope(|| self.inner.call(request));
+
+        let context = self.context.clone();
+        let request_span = span.clone();
+        Box::pin(
+            async move {
+                //Only covers the response head, streamed bodies may take longer.
+                let result = match context.max_request_duration {
+                    Some(max_request_duration) => match tokio::time::timeout(max_request_duration, future).await {
+                        Ok(result) => result,
+                        Err(_) => {
+                            tracing::warn!(?max_request_duration, "Request took too long.");
+                            //The client sent its request in time, so this is the server's failure rather than a 408.
+                            Ok(Problem::new(StatusCode::SERVICE_UNAVAILABLE)
+                                .with_detail("Request took too long.")
+                                .into_response())
+                        }
+                    },
+                    None => future.await,
+                };
+                let latency = started.elapsed();
+                request_span.record("latency_ms", latency.as_secs_f64() * 1000.0);
+
+                //The services guard their handlers, so errors and panics have already become responses.
+                let response = result?;
+                let response = echo_request_id(response, request_id_header);
+                let status = response.status();
+                request_span.record("status", status.as_u16());
+                tracing::info!("Request completed.");
+
+                let route = route_label(&response);
+                if let Some(metrics) = &context.metrics {
+                    metrics.observe_request(&method, &route, status, latency);
+                }
+
+                let mut access_log_entry = access_log_entry;
+                if let Some(entry) = &mut access_log_entry {
+                    entry.status = status.as_u16();
+                    entry.latency = latency;
+                }
+                if access_log_entry.is_none() && context.metrics.is_none() {
+                    return Ok(response);
+                }
+
+                //Sizes are only known once streamed bodies finish.
+                let (parts, body) = response.into_parts();
+                let body = ObservedBody::new(
+                    body,
+                    Box::new(move |bytes| {
+                        if let Some(metrics) = &context.metrics {
+                            metrics.observe_response_size(&method, &route, status, bytes);
+                        }
+                        if let (Some(mut entry), Some(access_log)) =
+                            (access_log_entry, &context.access_log)
+                        {
+                            entry.bytes = bytes;
+                            access_log.record(&entry);
+                        }
+                    }),
+                );
+                Ok(Response::from_parts(parts, body.boxed()))
+            }
+            .instrument(span),
+        )
+    }
+}
+
+//For logs. Unix socket peers without an address show as "unix".
+pub(crate) fn peer_label<T: std::fmt::Display>(peer: Option<T>) -> String {
+    match peer {
+        Some(peer) => peer.to_string(),
+        None => "unix".to_string(),
+    }
+}
+
+//Handlers may set their own X-Request-Id, e.g. when relaying an upstream response.
+fn echo_request_id(
+    mut response: Response<HandlerBody>,
+    request_id_header: Option<HeaderValue>,
+) -> Response<HandlerBody> {
+    if let Some(value) = request_id_header {
+        if !response.headers().contains_key(HEADER_X_REQUEST_ID) {
+            response.headers_mut().insert(HEADER_X_REQUEST_ID, value);
+        }
+    }
+    response
+}
+
+type BodyCompletion = Box<dyn FnOnce(u64) + Send + Sync>;
+
+//Counts the bytes sent and reports the total once the body ends or is dropped.
+pub(crate) struct ObservedBody {
+    inner: HandlerBody,
+    bytes: u64,
+    on_complete: Option<BodyCompletion>,
+}
+
+impl ObservedBody {
+    pub(crate) fn new(inner: HandlerBody, on_complete: BodyCompletion) -> ObservedBody {
+        ObservedBody {
+            inner,
+            bytes: 0,
+            on_complete: Some(on_complete),
+        }
+    }
+
+    fn complete(&mut self) {
+        if let Some(on_complete) = self.on_complete.take() {
+            on_complete(self.bytes);
+        }
+    }
+}
+
+impl Body for ObservedBody {
+    type Data = Bytes;
+    type Error = HandlerError;
+
+    fn poll_frame(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+    ) -> Poll<Option<Result<Frame<Bytes>, HandlerError>>> {
+        let poll = Pin::new(&mut self.inner).poll_frame(cx);
+        match &poll {
+            Poll::Ready(Some(Ok(frame))) => {
+                if let Some(data) = frame.data_ref() {
+                    self.bytes += data.len() as u64;
+                }
+            }
+            Poll::Ready(_) => self.complete(),
+            Poll::Pending => (),
+        }
+        poll
+    }
+
+    fn is_end_stream(&self) -> bool {
+        self.inner.is_end_stream()
+    }
+
+    fn size_hint(&self) -> SizeHint {
+        self.inner.size_hint()
+    }
+}
+
+impl Drop for ObservedBody {
+    fn drop(&mut self) {
+        self.complete();
+    }
+}
+
+//Time of the last read or write on a connection, used to close idle keep-alive connections.
+#[derive(Clone)]
+pub(crate) struct Activity {
+    started: Instant,
+    last_millis: Arc<AtomicU64>,
+}
+
+impl Activity {
+    pub(crate) fn new() -> Activity {
+        Activity {
+            started: Instant::now(),
+            last_millis: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn touch(&self) {
+        let millis = self.started.elapsed().as_millis() as u64;
+        self.last_millis.store(millis, Ordering::Relaxed);
+    }
+
+    pub(crate) fn idle_for(&self) -> Duration {
+        let last = Duration::from_millis(self.last_millis.load(Ordering::Relaxed));
+        self.started.elapsed().saturating_sub(last)
+    }
+}
+
+pub(crate) struct ActivityTracked<T> {
+    inner: T,
+    activity: Activity,
+}
+
+impl<T> ActivityTracked<T> {
+    pub(crate) fn new(inner: T, activity: Activity) -> ActivityTracked<T> {
+        ActivityTracked { inner, activity }
+    }
+}
+
+impl<T: AsyncRead + Unpin> AsyncRead for ActivityTracked<T> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let poll = Pin::new(&mut self.inner).poll_read(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+}
+
+impl<T: AsyncWrite + Unpin> AsyncWrite for ActivityTracked<T> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let poll = Pin::new(&mut self.inner).poll_write(cx, buf);
+        if poll.is_ready() {
+            self.activity.touch();
+        }
+        poll
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::*;
+
+    #[tokio::test]
+    async fn reads_and_writes_count_as_activity() {
+        let (client, server) = tokio::io::duplex(64);
+        let activity = Activity::new();
+        let mut tracked = ActivityTracked::new(server, activity.clone());
+        let mut client = client;
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        assert!(activity.idle_for() >= Duration::from_millis(50));
+        tracked.write_all(b"ping").await.unwrap();
+        assert!(activity.idle_for() < Duration::from_millis(50));
+
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        client.write_all(b"pong").await.unwrap();
+        let mut buffer = [0; 4];
+        tracked.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(&buffer, b"pong");
+        assert!(activity.idle_for() < Duration::from_millis(50));
+    }
+}
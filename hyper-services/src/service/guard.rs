@@ -0,0 +1,168 @@
+use std::{any::Any, future::Future, panic::AssertUnwindSafe, sync::Arc};
+
+use futures_util::FutureExt;
+use hyper::{Method, Request, Response, Uri};
+
+use crate::{
+    commons::{HandlerBody, HandlerError, HandlerFuture, HandlerResult},
+    http_error::{error_chain, problem_for_handler_error},
+    problem::Problem,
+    request_id::RequestId,
+    response_building::server_side_failure,
+};
+
+//What is known about a request once the handler has taken ownership of it.
+#[derive(Debug, Clone)]
+pub struct ErrorContext {
+    pub method: Method,
+    pub uri: Uri,
+    pub request_id: Option<RequestId>,
+}
+
+impl ErrorContext {
+    pub(crate) fn from_request<T>(request: &Request<T>) -> ErrorContext {
+        ErrorContext {
+            method: request.method().clone(),
+            uri: request.uri().clone(),
+            request_id: request.extensions().get::<RequestId>().cloned(),
+        }
+    }
+}
+
+//Receives every error a handler returns along with the problem that would be sent, and returns the problem to send.
+pub type ErrorHook = Arc<dyn Fn(&HandlerError, &ErrorContext, Problem) -> Problem + Send + Sync>;
+
+//Called with the panic message after a handler panicked, e.g. to raise an alert.
+pub type PanicHook = Arc<dyn Fn(&str, &ErrorContext) + Send + Sync>;
+
+#[derive(Clone, Default)]
+pub(crate) struct ServiceHooks {
+    pub(crate) error_hook: Option<ErrorHook>,
+    pub(crate) panic_hook: Option<PanicHook>,
+}
+
+//Turns handler errors and panics into responses, so hyper never sees an Err and drops the connection.
+pub(crate) fn guard<F>(future: F, context: ErrorContext, hooks: ServiceHooks) -> HandlerFuture
+where
+    F: Future<Output = HandlerResult> + Send + 'static,
+{
+    Box::pin(async move {
+        match AssertUnwindSafe(future).catch_unwind().await {
+            Ok(Ok(response)) => Ok(response),
+            Ok(Err(error)) => Ok(error_response(&error, &context, &hooks)),
+            Err(payload) => Ok(panic_response(payload, &context, &hooks)),
+        }
+    })
+}
+
+fn error_response(
+    error: &HandlerError,
+    context: &ErrorContext,
+    hooks: &ServiceHooks,
+) -> Response<HandlerBody> {
+    let problem = problem_for_handler_error(error);
+    let chain = error_chain(error.as_ref());
+    match problem.status().is_server_error() {
+        true => tracing::error!(method = %context.method, uri = %context.uri, error = chain, "Handler failed."),
+        false => tracing::debug!(method = %context.method, uri = %context.uri, error = chain, "Handler rejected request."),
+    }
+
+    let problem = match &hooks.error_hook {
+        Some(hook) => hook(error, context, problem),
+        None => problem,
+    };
+    problem.into_response()
+}
+
+fn panic_response(
+    payload: Box<dyn Any + Send>,
+    context: &ErrorContext,
+    hooks: &ServiceHooks,
+) -> Response<HandlerBody> {
+    let message = match payload.downcast_ref::<&str>() {
+        Some(message) => message.to_string(),
+        None => match payload.downcast_ref::<String>() {
+            Some(message) => message.clone(),
+            None => "Unknown panic payload.".to_string(),
+        },
+    };
+    tracing::error!(
+        method = %context.method,
+        uri = %context.uri,
+        request_id = context.request_id.as_ref().map(|id| id.as_str()),
+        panic = message,
+        "Handler panicked."
+    );
+
+    if let Some(hook) = &hooks.panic_hook {
+        hook(&message, context);
+    }
+    server_side_failure()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Mutex;
+
+    use http_body_util::BodyExt;
+    use hyper::StatusCode;
+
+    use super::*;
+    use crate::{http_error::HttpError, response_building::bytes_to_boxed_body};
+
+    fn context() -> ErrorContext {
+        ErrorContext {
+            method: Method::POST,
+            uri: Uri::from_static("/orders"),
+            request_id: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn passes_responses_through() {
+        let future = async { Ok(Response::new(bytes_to_boxed_body("done"))) };
+        let response = guard(future, context(), ServiceHooks::default()).await.unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn error_hook_sees_error_and_replaces_problem() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let hooks = ServiceHooks {
+            error_hook: Some(Arc::new(move |error, context, problem| {
+                recorded.lock().unwrap().push(format!("{} {} {}", context.method, context.uri, error));
+                problem.with_extension("support", "help@example.com")
+            })),
+            panic_hook: None,
+        };
+
+        let future = async { Err(HttpError::conflict("Order exists.").into()) };
+        let response = guard(future, context(), hooks).await.unwrap();
+        assert_eq!(response.status(), StatusCode::CONFLICT);
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        let problem: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(problem["support"], "help@example.com");
+        assert_eq!(*seen.lock().unwrap(), ["POST /orders 409 Conflict: Order exists."]);
+    }
+
+    async fn out_of_stock() -> HandlerResult {
+        panic!("Out of stock.")
+    }
+
+    #[tokio::test]
+    async fn panic_hook_gets_message() {
+        let seen = Arc::new(Mutex::new(Vec::new()));
+        let recorded = seen.clone();
+        let hooks = ServiceHooks {
+            error_hook: None,
+            panic_hook: Some(Arc::new(move |message, context| {
+                recorded.lock().unwrap().push(format!("{} {}", context.uri, message));
+            })),
+        };
+
+        let response = guard(out_of_stock(), context(), hooks).await.unwrap();
+        assert_eq!(response.status(), StatusCode::INTERNAL_SERVER_ERROR);
+        assert_eq!(*seen.lock().unwrap(), ["/orders Out of stock."]);
+    }
+}
@@ -0,0 +1,203 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    sync::Arc,
+};
+
+use tokio::task::JoinSet;
+
+use hyper::{body::Incoming, header, service::Service, Request, Response, StatusCode, Uri};
+
+use crate::{
+    commons::{HandlerBody, HandlerError, HandlerFuture},
+    response_building::{bad_request, empty_body},
+    service::{
+        certificates::TlsCerts,
+        connection::ServerContext,
+        spawn::{accept_loop, bind_with_retry, tls_acceptor, ConnectionProperties},
+    },
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ListenerMode {
+    //Serves the service.
+    Serve,
+    //Answers every request with a permanent redirect to the same host and path over HTTPS.
+    //None for the port means the default 443.
+    RedirectToHttps { https_port: Option<u16> },
+}
+
+pub struct Listener {
+    pub addr: SocketAddr,
+    pub tls: Option<TlsCerts>,
+    pub mode: ListenerMode,
+}
+
+impl Listener {
+    pub fn http(addr: SocketAddr) -> Listener {
+        Listener {
+            addr,
+            tls: None,
+            mode: ListenerMode::Serve,
+        }
+    }
+
+    pub fn https(addr: SocketAddr, tls: TlsCerts) -> Listener {
+        Listener {
+            addr,
+            tls: Some(tls),
+            mode: ListenerMode::Serve,
+        }
+    }
+
+    pub fn redirect_to_https(addr: SocketAddr, https_port: Option<u16>) -> Listener {
+        Listener {
+            addr,
+            tls: None,
+            mode: ListenerMode::RedirectToHttps { https_port },
+        }
+    }
+
+    //The IPv4 and IPv6 wildcard addresses on one port.
+    pub fn dual_stack(port: u16) -> [SocketAddr; 2] {
+        [
+            SocketAddr::new(IpAddr::V4(Ipv4Addr::UNSPECIFIED), port),
+            SocketAddr::new(IpAddr::V6(Ipv6Addr::UNSPECIFIED), port),
+        ]
+    }
+}
+
+//Binds every listener, then serves them all with one shared context. TLS comes from the listeners, props.tls is ignored.
+//props.sni applies to the listeners with TLS.
+//Returns once any listener fails, after stopping the others.
+pub(crate) async fn spawn_listeners<S>(
+    listeners: Vec<Listener>,
+    service: S,
+    mut props: ConnectionProperties,
+) -> Result<(), HandlerError>
+where
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+{
+    if listeners.is_empty() {
+        return Err("No listeners to serve on.".into());
+    }
+    if props.tls.take().is_some() {
+        tracing::warn!("ConnectionProperties::tls is ignored when serving on several listeners.");
+    }
+    let sni = props.sni.take();
+    let context = Arc::new(ServerContext::from_properties(props));
+
+    let addrs: Vec<SocketAddr> = listeners.iter().map(|listener| listener.addr).collect();
+    //Dropping the set aborts the listeners already running, also when a later one can't be set up.
+    let mut tasks = JoinSet::new();
+    for listener in listeners {
+        let listener_sni = match listener.tls {
+            Some(_) => sni.clone(),
+            None => None,
+        };
+        let tls_handler = tls_acceptor(listener.tls, listener_sni)?;
+        let only_v6 = needs_only_v6(listener.addr, &addrs);
+        let bound = bind_with_retry(listener.addr, only_v6, context.metrics.as_ref()).await;
+        let context = context.clone();
+        match listener.mode {
+            ListenerMode::Serve => tasks.spawn(accept_loop(bound, tls_handler, service.clone(), context)),
+            ListenerMode::RedirectToHttps { https_port } => tasks.spawn(accept_loop(
+                bound,
+                tls_handler,
+                HttpsRedirect { https_port },
+                context,
+            )),
+        };
+    }
+    while let Some(joined) = tasks.join_next().await {
+        match joined {
+            Ok(Ok(())) => (),
+            Ok(Err(e)) => return Err(e),
+            Err(e) => return Err(Box::new(e)),
+        }
+    }
+    Ok(())
+}
+
+//An IPv6 socket also takes IPv4 connections by default, which collides with an IPv4 listener on the same port.
+//Only then is it restricted to IPv6, otherwise the system default stays.
+fn needs_only_v6(addr: SocketAddr, addrs: &[SocketAddr]) -> bool {
+    addr.is_ipv6() && addrs.iter().any(|other| other.is_ipv4() && other.port() == addr.port())
+}
+
+#[derive(Clone)]
+struct HttpsRedirect {
+    https_port: Option<u16>,
+}
+
+impl Service<Request<Incoming>> for HttpsRedirect {
+    type Response = Response<HandlerBody>;
+    type Error = HandlerError;
+    type Future = HandlerFuture;
+
+    fn call(&self, request: Request<Incoming>) -> Self::Future {
+        let response = https_redirect(&request, self.https_port);
+        Box::pin(async move { Ok(response) })
+    }
+}
+
+//308 keeps the method and body, unlike 301.
+pub fn https_redirect<T>(request: &Request<T>, https_port: Option<u16>) -> Response<HandlerBody> {
+    let host = request
+        .headers()
+        .get(header::HOST)
+        .and_then(|host| host.to_str().ok())
+        .or_else(|| request.uri().host());
+    let host = match host.and_then(|host| host.parse::<hyper::http::uri::Authority>().ok()) {
+        Some(authority) => authority.host().to_string(),
+        None => return bad_request(),
+    };
+    let authority = match https_port {
+        Some(443) | None => host,
+        Some(port) => format!("{}:{}", host, port),
+    };
+    let path_and_query = request
+        .uri()
+        .path_and_query()
+        .map(|path_and_query| path_and_query.as_str())
+        .unwrap_or("/");
+    let location = match format!("https://{}{}", authority, path_and_query).parse::<Uri>() {
+        Ok(location) => location,
+        Err(_) => return bad_request(),
+    };
+    Response::builder()
+        .status(StatusCode::PERMANENT_REDIRECT)
+        .header(header::LOCATION, location.to_string())
+        .body(empty_body())
+        .expect("Should produce response.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn location(host: &str, uri: &str, https_port: Option<u16>) -> String {
+        let request = Request::builder().uri(uri).header(header::HOST, host).body(()).unwrap();
+        let response = https_redirect(&request, https_port);
+        assert_eq!(response.status(), StatusCode::PERMANENT_REDIRECT);
+        response.headers()[header::LOCATION].to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn redirects_preserving_host_and_path() {
+        assert_eq!(location("example.com", "/a/b?c=d", None), "https://example.com/a/b?c=d");
+        assert_eq!(location("example.com:8080", "/", Some(8443)), "https://example.com:8443/");
+        assert_eq!(location("[::1]:80", "/x", Some(443)), "https://[::1]/x");
+
+        let request = Request::builder().uri("/").body(()).unwrap();
+        assert_eq!(https_redirect(&request, None).status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn restricts_ipv6_only_next_to_ipv4_on_the_same_port() {
+        let [v4, v6] = Listener::dual_stack(8080);
+        assert!(needs_only_v6(v6, &[v4, v6]));
+        assert!(!needs_only_v6(v4, &[v4, v6]));
+        assert!(!needs_only_v6(v6, &[v6]));
+        assert!(!needs_only_v6(v6, &["0.0.0.0:8081".parse().unwrap(), v6]));
+    }
+}
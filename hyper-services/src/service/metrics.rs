@@ -0,0 +1,240 @@
+use std::time::Duration;
+
+use hyper::{Method, Response, StatusCode};
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+use crate::{commons::HandlerBody, response_building::bytes_to_boxed_body};
+
+const UNMATCHED_ROUTE: &str = "unmatched";
+const LABELS: [&str; 3] = ["method", "route", "status"];
+const SIZE_BUCKETS: [f64; 8] = [
+    100.0, 1_000.0, 10_000.0, 100_000.0, 1_000_000.0, 10_000_000.0, 100_000_000.0, 1_000_000_000.0,
+];
+
+//Handlers can insert this into the response extensions to label the request by route template instead of raw path.
+#[derive(Debug, Clone)]
+pub struct MetricsRoute(pub String);
+
+impl MetricsRoute {
+    pub fn new<S: Into<String>>(route: S) -> MetricsRoute {
+        MetricsRoute(route.into())
+    }
+}
+
+pub struct Metrics {
+    registry: Registry,
+    requests_total: IntCounterVec,
+    request_duration_seconds: HistogramVec,
+    response_size_bytes: HistogramVec,
+    active_connections: IntGauge,
+    tls_handshake_failures_total: IntCounter,
+    bind_retries_total: IntCounter,
+}
+
+impl Metrics {
+    pub fn new() -> Result<Metrics, prometheus::Error> {
+        Metrics::with_registry(Registry::new())
+    }
+
+    //Use an existing registry, e.g. one the application also registers its own metrics in.
+    pub fn with_registry(registry: Registry) -> Result<Metrics, prometheus::Error> {
+        let requests_total = IntCounterVec::new(
+            Opts::new("http_requests_total", "Number of HTTP requests served."),
+            &LABELS,
+        )?;
+        let request_duration_seconds = HistogramVec::new(
+            HistogramOpts::new(
+                "http_request_duration_seconds",
+                "Time from receiving the request until the response headers are ready.",
+            ),
+            &LABELS,
+        )?;
+        let response_size_bytes = HistogramVec::new(
+            HistogramOpts::new("http_response_size_bytes", "Size of response bodies.")
+                .buckets(SIZE_BUCKETS.to_vec()),
+            &LABELS,
+        )?;
+        let active_connections =
+            IntGauge::new("http_active_connections", "Connections currently open.")?;
+        let tls_handshake_failures_total = IntCounter::new(
+            "http_tls_handshake_failures_total",
+            "Number of failed TLS handshakes.",
+        )?;
+        let bind_retries_total = IntCounter::new(
+            "http_bind_retries_total",
+            "Number of failed attempts to bind the listening socket.",
+        )?;
+
+        registry.register(Box::new(requests_total.clone()))?;
+        registry.register(Box::new(request_duration_seconds.clone()))?;
+        registry.register(Box::new(response_size_bytes.clone()))?;
+        registry.register(Box::new(active_connections.clone()))?;
+        registry.register(Box::new(tls_handshake_failures_total.clone()))?;
+        registry.register(Box::new(bind_retries_total.clone()))?;
+
+        Ok(Metrics {
+            registry,
+            requests_total,
+            request_duration_seconds,
+            response_size_bytes,
+            active_connections,
+            tls_handshake_failures_total,
+            bind_retries_total,
+        })
+    }
+
+    pub fn registry(&self) -> &Registry {
+        &self.registry
+    }
+
+    pub(crate) fn observe_request(
+        &self,
+        method: &Method,
+        route: &str,
+        status: StatusCode,
+        duration: Duration,
+    ) {
+        let labels = [method.as_str(), route, status.as_str()];
+        self.requests_total.with_label_values(&labels).inc();
+        self.request_duration_seconds
+            .with_label_values(&labels)
+            .observe(duration.as_secs_f64());
+    }
+
+    pub(crate) fn observe_response_size(
+        &self,
+        method: &Method,
+        route: &str,
+        status: StatusCode,
+        bytes: u64,
+    ) {
+        self.response_size_bytes
+            .with_label_values(&[method.as_str(), route, status.as_str()])
+            .observe(bytes as f64);
+    }
+
+    pub(crate) fn connection_opened(&self) {
+        self.active_connections.inc();
+    }
+
+    pub(crate) fn connection_closed(&self) {
+        self.active_connections.dec();
+    }
+
+    pub(crate) fn tls_handshake_failed(&self) {
+        self.tls_handshake_failures_total.inc();
+    }
+
+    pub(crate) fn bind_retried(&self) {
+        self.bind_retries_total.inc();
+    }
+
+    pub fn encode(&self) -> Result<String, prometheus::Error> {
+        let mut buffer = Vec::new();
+        TextEncoder::new().encode(&self.registry.gather(), &mut buffer)?;
+        String::from_utf8(buffer).map_err(|e| prometheus::Error::Msg(e.to_string()))
+    }
+}
+
+//Responses without a route are grouped, so that raw paths from scanners can't blow up the label cardinality.
+pub(crate) fn route_label<T>(response: &Response<T>) -> String {
+    match response.extensions().get::<MetricsRoute>() {
+        Some(route) => route.0.clone(),
+        None => UNMATCHED_ROUTE.to_string(),
+    }
+}
+
+//Mount at /metrics from a handler.
+pub fn metrics_response(metrics: &Metrics) -> Response<HandlerBody> {
+    match metrics.encode() {
+        Ok(text) => Response::builder()
+            .status(StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)
+            .body(bytes_to_boxed_body(text))
+            .expect("Should produce response."),
+        Err(e) => {
+            tracing::error!(error = %e, "Couldn't encode metrics.");
+            crate::response_building::server_side_failure()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use hyper::{body::Incoming, Request};
+
+    use super::*;
+    use crate::{
+        commons::HandlerResult,
+        service::{
+            spawn::ConnectionProperties,
+            stateful_service::{StatefulHandler, StatefulService},
+        },
+        testing::TestClient,
+    };
+
+    #[test]
+    fn labels_by_route_or_groups_as_unmatched() {
+        let mut response = Response::new(());
+        assert_eq!(route_label(&response), "unmatched");
+        *response.status_mut() = StatusCode::NOT_FOUND;
+        assert_eq!(route_label(&response), "unmatched");
+        response.extensions_mut().insert(MetricsRoute::new("/users/{id}"));
+        assert_eq!(route_label(&response), "/users/{id}");
+    }
+
+    #[test]
+    fn encodes_observations() {
+        let metrics = Metrics::new().unwrap();
+        metrics.observe_request(&Method::GET, "/users/{id}", StatusCode::OK, Duration::from_millis(5));
+        metrics.observe_response_size(&Method::GET, "/users/{id}", StatusCode::OK, 512);
+        metrics.connection_opened();
+
+        let text = metrics.encode().unwrap();
+        assert!(text.contains(r#"http_requests_total{method="GET",route="/users/{id}",status="200"} 1"#));
+        assert!(text.contains(r#"http_response_size_bytes_sum{method="GET",route="/users/{id}",status="200"} 512"#));
+        assert!(text.contains("http_active_connections 1"));
+    }
+
+    #[derive(Clone)]
+    struct Routes(Arc<Metrics>);
+
+    impl StatefulHandler for Routes {
+        async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+            match request.uri().path() {
+                "/metrics" => Ok(metrics_response(&self.0)),
+                path if path.starts_with("/users/") => {
+                    let mut response = Response::new(bytes_to_boxed_body("user"));
+                    response.extensions_mut().insert(MetricsRoute::new("/users/{id}"));
+                    Ok(response)
+                }
+                _ => Ok(Response::new(bytes_to_boxed_body("other"))),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn serves_request_metrics() {
+        let metrics = Arc::new(Metrics::new().unwrap());
+        let props = ConnectionProperties {
+            metrics: Some(metrics.clone()),
+            ..Default::default()
+        };
+        let client = TestClient::with_properties(StatefulService::create(Routes(metrics)), props);
+        client.get("/users/1").send().await.assert_text("user");
+        client.get("/users/2").send().await.assert_text("user");
+        client.get("/wp-login.php").send().await.assert_text("other");
+
+        let response = client.get("/metrics").send().await;
+        response.assert_status(StatusCode::OK).assert_header("content-type", prometheus::TEXT_FORMAT);
+        let text = response.text();
+        assert!(text.contains(r#"http_requests_total{method="GET",route="/users/{id}",status="200"} 2"#));
+        assert!(text.contains(r#"http_requests_total{method="GET",route="unmatched",status="200"} 1"#));
+        assert!(!text.contains("wp-login"));
+    }
+}
@@ -1,4 +1,14 @@
 pub mod stateful_service;
 pub mod stateless_service;
 pub mod certificates;
-pub mod spawn;
\ No newline at end of file
+pub mod spawn;
+pub mod connection;
+pub mod access_log;
+pub mod metrics;
+pub mod guard;
+pub mod listeners;
+pub mod state;
+#[cfg(unix)]
+pub mod unix;
+#[cfg(unix)]
+pub mod socket_activation;
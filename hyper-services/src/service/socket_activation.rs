@@ -0,0 +1,127 @@
+use std::{
+    os::fd::{FromRawFd, OwnedFd, RawFd},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use socket2::{SockRef, Type};
+
+use crate::commons::HandlerError;
+
+//The first descriptor passed by systemd (SD_LISTEN_FDS_START).
+const LISTEN_FDS_START: RawFd = 3;
+
+//Set by the first call, so the descriptors can't end up owned twice.
+static TAKEN: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug)]
+pub enum ActivatedListener {
+    Tcp(std::net::TcpListener),
+    Unix(std::os::unix::net::UnixListener),
+}
+
+#[derive(Debug)]
+pub struct ActivatedSocket {
+    //From FileDescriptorName= in the socket unit, if set.
+    pub name: Option<String>,
+    pub listener: ActivatedListener,
+}
+
+//Takes over the listening sockets passed through LISTEN_FDS and LISTEN_PID. Returns nothing if the process
+//wasn't socket activated, and on every call after the first. The environment is left alone, since changing it
+//isn't safe once threads run. Children ignore the variables anyway because LISTEN_PID doesn't match them, and
+//the descriptors are closed on exec.
+pub fn activated_sockets() -> Result<Vec<ActivatedSocket>, HandlerError> {
+    if TAKEN.swap(true, Ordering::SeqCst) {
+        return Ok(Vec::new());
+    }
+    let pid = match std::env::var("LISTEN_PID") {
+        Ok(pid) => pid,
+        Err(_) => return Ok(Vec::new()),
+    };
+    if pid.trim().parse::<u32>()? != std::process::id() {
+        tracing::debug!(pid, "LISTEN_PID belongs to another process.");
+        return Ok(Vec::new());
+    }
+    let count = match std::env::var("LISTEN_FDS") {
+        Ok(count) => count.trim().parse::<RawFd>()?,
+        Err(_) => return Ok(Vec::new()),
+    };
+    let names: Vec<String> = std::env::var("LISTEN_FDNAMES")
+        .map(|names| names.split(':').map(|name| name.to_string()).collect())
+        .unwrap_or_default();
+
+    let mut sockets = Vec::new();
+    for (index, raw_fd) in (LISTEN_FDS_START..LISTEN_FDS_START + count).enumerate() {
+        //Safety: systemd hands these descriptors to this process, and TAKEN makes sure nothing else claims them.
+        let fd = unsafe { OwnedFd::from_raw_fd(raw_fd) };
+        SockRef::from(&fd).set_cloexec(true)?;
+        let listener = classify(fd)?;
+        tracing::info!(fd = raw_fd, ?listener, "Took over activated socket.");
+        sockets.push(ActivatedSocket {
+            name: names.get(index).cloned(),
+            listener,
+        });
+    }
+    Ok(sockets)
+}
+
+//Only listening stream sockets are taken, e.g. not the datagram sockets or accepted connections of Accept=yes units.
+fn classify(fd: OwnedFd) -> Result<ActivatedListener, HandlerError> {
+    let socket = SockRef::from(&fd);
+    if socket.r#type()? != Type::STREAM || !is_listening(&socket)? {
+        return Err(format!("Activated descriptor {:?} isn't a listening stream socket.", fd).into());
+    }
+    let address = socket.local_addr()?;
+    if address.is_unix() {
+        let unix = std::os::unix::net::UnixListener::from(fd);
+        unix.set_nonblocking(true)?;
+        return Ok(ActivatedListener::Unix(unix));
+    }
+    if address.as_socket().is_none() {
+        return Err(format!("Activated descriptor {:?} is neither TCP nor a Unix socket.", fd).into());
+    }
+    let tcp = std::net::TcpListener::from(fd);
+    tcp.set_nonblocking(true)?;
+    Ok(ActivatedListener::Tcp(tcp))
+}
+
+#[cfg(any(target_os = "android", target_os = "freebsd", target_os = "linux"))]
+fn is_listening(socket: &SockRef) -> std::io::Result<bool> {
+    socket.is_listener()
+}
+
+//SO_ACCEPTCONN isn't available, accepting fails later instead.
+#[cfg(not(any(target_os = "android", target_os = "freebsd", target_os = "linux")))]
+fn is_listening(_socket: &SockRef) -> std::io::Result<bool> {
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_listener_families_apart() {
+        let tcp = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        assert!(matches!(classify(OwnedFd::from(tcp)).unwrap(), ActivatedListener::Tcp(_)));
+
+        let path = std::env::temp_dir().join(format!("hyper-services-activation-{}.sock", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let unix = std::os::unix::net::UnixListener::bind(&path).unwrap();
+        assert!(matches!(classify(OwnedFd::from(unix)).unwrap(), ActivatedListener::Unix(_)));
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[test]
+    fn rejects_sockets_that_arent_listening() {
+        let udp = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        assert!(classify(OwnedFd::from(udp)).is_err());
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let connection = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        assert!(classify(OwnedFd::from(connection)).is_err());
+
+        let (unix, _) = std::os::unix::net::UnixStream::pair().unwrap();
+        assert!(classify(OwnedFd::from(unix)).is_err());
+    }
+}
@@ -1,30 +1,93 @@
 
-use std::net::{IpAddr, SocketAddr};
+use std::{future::Future, net::{IpAddr, SocketAddr}, pin::Pin, sync::Arc, time::Duration};
 
 use hyper::{
-    body::{Body, Incoming},
+    body::Incoming,
     server::conn::http1,
-    service::HttpService,
+    service::Service,
+    Request, Response,
 };
 use hyper_util::rt::{TokioIo, TokioTimer};
 
+use socket2::{Domain, Protocol, Socket, Type};
 use tokio::net::TcpListener;
 use tokio_rustls::{TlsAcceptor, rustls::ServerConfig};
+use tracing::Instrument;
 
-use crate::service::certificates::TlsCerts;
+use crate::{
+    access_control::AccessControl,
+    commons::{HandlerBody, HandlerError, HandlerFuture},
+    request_processing::client_address::TrustedProxies,
+    service::{
+        access_log::AccessLog,
+        certificates::{SniResolver, TlsCerts},
+        connection::{peer_label, Activity, ActivityTracked, ConnectionInfo, ConnectionService, ServerContext},
+        metrics::Metrics,
+    },
+};
+
+const DEFAULT_TLS_HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_HEADER_READ_TIMEOUT: Duration = Duration::from_secs(30);
+//hyper refuses smaller buffers.
+const MINIMUM_MAX_HEADER_SIZE: usize = 8192;
+//Same as tokio's TcpListener::bind.
+const LISTEN_BACKLOG: i32 = 1024;
 
 pub struct ConnectionProperties
 {
     pub with_upgrades:bool,
-    pub tls:Option<TlsCerts>
+    pub tls:Option<TlsCerts>,
+    //Picks certificates by server name, e.g. VirtualHosts::sni_resolver. Turns TLS on, with tls as the fallback certificate.
+    pub sni:Option<SniResolver>,
+    //Connections from addresses this denies are dropped at accept time.
+    pub access_control:Option<AccessControl>,
+    //Peers whose forwarded headers are used to resolve the client address.
+    pub trusted_proxies:Option<TrustedProxies>,
+    pub access_log:Option<AccessLog>,
+    //Shared with the handler serving metrics_response.
+    pub metrics:Option<Arc<Metrics>>,
+    //None disables the timeouts and limits below, unless noted otherwise.
+    pub tls_handshake_timeout:Option<Duration>,
+    //Time allowed to send a complete request head. Also bounds how long a kept-alive connection waits for the next request to start.
+    pub header_read_timeout:Option<Duration>,
+    pub keep_alive:bool,
+    //Kept-alive connections without any traffic for this long are closed gracefully.
+    pub idle_timeout:Option<Duration>,
+    //Size of the read buffer, which bounds the request head. At least 8192. None keeps hyper's default of about 400kB.
+    pub max_header_size:Option<usize>,
+    //None keeps hyper's default of 100.
+    pub max_headers:Option<usize>,
+    //Handlers that don't produce a response head in time are answered with 503.
+    pub max_request_duration:Option<Duration>,
+    pub half_close:bool,
+    pub title_case_headers:bool,
+    //Address Unix socket peers are given for access control, trusted proxies and ClientAddr, e.g. 127.0.0.1 when
+    //only a local proxy can reach the socket. Without it they have none: access control refuses them and
+    //forwarded headers are ignored.
+    pub unix_peer_addr:Option<IpAddr>
 }
 
 impl Default for ConnectionProperties
 {
     fn default() -> Self {
-        Self { 
+        ConnectionProperties {
             with_upgrades: false,
-            tls: None
+            tls: None,
+            sni: None,
+            access_control: None,
+            trusted_proxies: None,
+            access_log: None,
+            metrics: None,
+            tls_handshake_timeout: Some(DEFAULT_TLS_HANDSHAKE_TIMEOUT),
+            header_read_timeout: Some(DEFAULT_HEADER_READ_TIMEOUT),
+            keep_alive: true,
+            idle_timeout: None,
+            max_header_size: None,
+            max_headers: None,
+            max_request_duration: None,
+            half_close: false,
+            title_case_headers: false,
+            unix_peer_addr: None,
         }
     }
 }
@@ -36,135 +99,345 @@ pub(crate) async fn spawn_server<S>(
     props: ConnectionProperties
 ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
 where
-    S: 'static + Clone + Send + HttpService<Incoming>,
-    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    S::ResBody: 'static,
-    <S::ResBody as Body>::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    <S as HttpService<hyper::body::Incoming>>::ResBody: Send,
-    <S as HttpService<hyper::body::Incoming>>::Future: Send,
-    <<S as HttpService<hyper::body::Incoming>>::ResBody as hyper::body::Body>::Data: Send,
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
 {
     let socket = SocketAddr::new(ip, port);
+    let listener = bind_with_retry(socket, false, props.metrics.as_ref()).await;
+    serve_listener(listener, service, props).await
+}
 
-    println!("Binding to {}:{}", ip, port);
+//Keeps retrying, e.g. while a previous instance still holds the port.
+pub(crate) async fn bind_with_retry(socket: SocketAddr, only_v6: bool, metrics: Option<&Arc<Metrics>>) -> TcpListener
+{
+    tracing::info!(%socket, "Binding.");
 
-    let listener: TcpListener;
     loop {
-        match TcpListener::bind(socket).await {
-            Ok(res) => {
-                listener = res;
-                break;
-            }
-            Err(_) => {
-                eprintln!("Couldn't bind port. Retrying.");
+        match bind(socket, only_v6) {
+            Ok(listener) => return listener,
+            Err(e) => {
+                tracing::warn!(%socket, error = %e, "Couldn't bind port. Retrying.");
+                if let Some(metrics) = metrics {
+                    metrics.bind_retried();
+                }
                 tokio::time::sleep(std::time::Duration::from_millis(1000)).await;
             }
         }
     }
+}
+
+//only_v6 lets an IPv6 wildcard listener share its port with an IPv4 one. Otherwise the system default applies.
+fn bind(socket: SocketAddr, only_v6: bool) -> std::io::Result<TcpListener>
+{
+    let tcp = Socket::new(Domain::for_address(socket), Type::STREAM, Some(Protocol::TCP))?;
+    if socket.is_ipv6() && only_v6 {
+        tcp.set_only_v6(true)?;
+    }
+    #[cfg(unix)]
+    tcp.set_reuse_address(true)?;
+    tcp.set_nonblocking(true)?;
+    tcp.bind(&socket.into())?;
+    tcp.listen(LISTEN_BACKLOG)?;
+    TcpListener::from_std(tcp.into())
+}
+
+//Serves on a listener that is already bound, e.g. one inherited through socket activation.
+pub(crate) async fn serve_listener<S>(
+    listener: TcpListener,
+    service: S,
+    mut props: ConnectionProperties
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+{
+    let tls_handler = tls_acceptor(props.tls.take(), props.sni.take())?;
+    let context = Arc::new(ServerContext::from_properties(props));
+    accept_loop(listener, tls_handler, service, context).await
+}
+
+pub(crate) async fn accept_loop<S>(
+    listener: TcpListener,
+    tls_handler: Option<TlsAcceptor>,
+    service: S,
+    context: Arc<ServerContext>
+) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+where
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+{
+    let socket = listener.local_addr()?;
+
+    tracing::info!(%socket, tls = tls_handler.is_some(), "Starting listen loop.");
+    loop {
+        match listener.accept().await {
+            Ok((tcp, peer_addr)) => {
+                let info = ConnectionInfo {
+                    peer_addr: Some(peer_addr),
+                    local_addr: Some(tcp.local_addr().unwrap_or(socket)),
+                    tls: tls_handler.is_some(),
+                    unix_peer: None,
+                    peer_is_trusted_proxy: context.trusts(Some(peer_addr.ip())),
+                };
+                serve_accepted(tcp, info, tls_handler.as_ref(), service.clone(), &context).await;
+
+                /*
+                //Old pattern was to spawn handler. Necessary still?
+                match props.with_upgrades
+                {
+                    true=>tokio::task::spawn(async move {handle_result(connection.with_upgrades().await)}),
+                    false=>tokio::task::spawn(async move {handle_result(connection.await)})
+                };
+                */
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Couldn't accept tcp, retrying.")
+            }
+        };
+    }
+}
 
-    let tls_handler = match props.tls
+//With an SNI resolver, tls only serves as the certificate for unknown server names.
+pub(crate) fn tls_acceptor(tls:Option<TlsCerts>, sni:Option<SniResolver>) -> Result<Option<TlsAcceptor>, Box<dyn std::error::Error + Send + Sync>>
+{
+    let builder = ServerConfig::builder().with_no_client_auth();
+    let server_config = match (tls, sni)
     {
-        Some(certs)=>{
+        (None, None)=>return Ok(None),
+        (tls, Some(resolver))=>{
+            let resolver = match tls
+            {
+                Some(certs)=>resolver.with_default(certs)?,
+                None=>resolver
+            };
+            builder.with_cert_resolver(Arc::new(resolver))
+        }
+        (Some(certs), None)=>{
              // Load public certificate.
-                        
-            let server_config = match ServerConfig::builder()
-                .with_no_client_auth()
+
+            match builder
                 .with_single_cert(certs.certs, certs.keys)
-                .map_err(|e|  std::io::Error::new(std::io::ErrorKind::Other, e.to_string()))
+                .map_err(|e|  std::io::Error::other(e.to_string()))
                 {
                     Ok(config)=>config,
                     Err(e)=>{
-                        eprintln!("Couldn't initialize tls handler");
+                        tracing::error!(error = %e, "Couldn't initialize tls handler");
                         return Err(Box::new(e));
                     }
-                };
-            
-            //This was causing failures. Didn't seem to iterate through the potential porotocls.
-            //server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
-            
-            let tls_acceptor = TlsAcceptor::from(std::sync::Arc::new(server_config));
-            Some(tls_acceptor)
+                }
         }
-        None=>None
     };
 
-    println!("Starting listen loop on {}:{}", ip, port);
-    loop {
-        match listener.accept().await {
-            Ok((tcp, _)) => {
-                
-                let clone = service.clone();
+    //This was causing failures. Didn't seem to iterate through the potential porotocls.
+    //server_config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec(), b"http/1.0".to_vec()];
 
-                match &tls_handler
-                {
-                    Some(tls)=>{
-                        let tls = tls.clone();
-
-                        tokio::spawn(async move {
-                            let tls_stream = match tls.accept(tcp).await {
-                                Ok(tls_stream) => tls_stream,
-                                Err(err) => {
-                                    eprintln!("failed to perform tls handshake: {err:#}");
-                                    return;
-                                }
-                            };
-
-                            service_connection(tls_stream, clone, props.with_upgrades).await
-                        });
+    let tls_acceptor = TlsAcceptor::from(Arc::new(server_config));
+    Ok(Some(tls_acceptor))
+}
+
+//Checks access, then serves the connection on its own task, after the TLS handshake if there is one.
+pub(crate) async fn serve_accepted<StreamType,S>(stream:StreamType, info:ConnectionInfo, tls_handler:Option<&TlsAcceptor>, service:S, context:&Arc<ServerContext>)
+where
+    S: 'static + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+    StreamType: 'static + tokio::io::AsyncRead+tokio::io::AsyncWrite+std::marker::Unpin+std::marker::Send
+{
+    let peer_addr = info.peer_addr;
+    let deferred_access_check = match context.admit(peer_addr.map(|peer| peer.ip())) {
+        Some(deferred_access_check) => deferred_access_check,
+        None => {
+            tracing::warn!(peer = %peer_label(peer_addr), "Rejected connection.");
+            return;
+        }
+    };
+
+    let clone = ConnectionService::new(
+        service,
+        info,
+        context.clone(),
+        deferred_access_check,
+    );
+    let context = context.clone();
+
+    match tls_handler
+    {
+        Some(tls)=>{
+            let tls = tls.clone();
+
+            tokio::spawn(async move {
+                let handshake = match context.tls_handshake_timeout {
+                    Some(timeout) => match tokio::time::timeout(timeout, tls.accept(stream)).await {
+                        Ok(handshake) => handshake,
+                        Err(_) => Err(std::io::Error::new(std::io::ErrorKind::TimedOut, "handshake timed out")),
                     },
-                    None=>{
-                        service_connection(tcp, clone, props.with_upgrades).await
+                    None => tls.accept(stream).await,
+                };
+                let tls_stream = match handshake {
+                    Ok(tls_stream) => tls_stream,
+                    Err(err) => {
+                        tracing::warn!(peer = %peer_label(peer_addr), "failed to perform tls handshake: {err:#}");
+                        if let Some(metrics) = &context.metrics {
+                            metrics.tls_handshake_failed();
+                        }
+                        return;
                     }
-                }
+                };
 
-                /*
-                //Old pattern was to spawn handler. Necessary still?
-                match props.with_upgrades
-                {
-                    true=>tokio::task::spawn(async move {handle_result(connection.with_upgrades().await)}),
-                    false=>tokio::task::spawn(async move {handle_result(connection.await)})
-                };     
-                */
-            }
-            Err(_) => {
-                eprintln!("Couldn't accept tcp, retrying.")
-            }
-        };
+                service_connection(tls_stream, clone, peer_addr, context).await
+            });
+        },
+        None=>{
+            service_connection(stream, clone, peer_addr, context).await
+        }
     }
 }
 
-async fn service_connection<StreamType,S>(stream:StreamType, service_clone:S, with_upgrades:bool)->()
+pub(crate) async fn service_connection<StreamType,S>(stream:StreamType, service_clone:S, peer_addr:Option<SocketAddr>, context:Arc<ServerContext>)
 where
-    S: 'static + Clone + Send + HttpService<Incoming>,
-    S::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    S::ResBody: 'static,
-    <S::ResBody as Body>::Error: Into<Box<dyn std::error::Error + Send + Sync>>,
-    <S as HttpService<hyper::body::Incoming>>::ResBody: Send,
-    <S as HttpService<hyper::body::Incoming>>::Future: Send,
-    <<S as HttpService<hyper::body::Incoming>>::ResBody as hyper::body::Body>::Data: Send,
+    S: 'static + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
     StreamType: 'static + tokio::io::AsyncRead+tokio::io::AsyncWrite+std::marker::Unpin+std::marker::Send
 {
     tokio::spawn(async move {
-            let io = TokioIo::new(stream);
+            let activity = Activity::new();
+            let io = TokioIo::new(ActivityTracked::new(stream, activity.clone()));
 
-            let connection=http1::Builder::new()
+            if let Some(metrics) = &context.metrics {
+                metrics.connection_opened();
+            }
+
+            let mut builder = http1::Builder::new();
+            builder
                 .timer(TokioTimer::new())
-                .serve_connection(io, service_clone);
+                .header_read_timeout(context.header_read_timeout)
+                .keep_alive(context.keep_alive)
+                .half_close(context.half_close)
+                .title_case_headers(context.title_case_headers);
+            if let Some(max_header_size) = context.max_header_size {
+                builder.max_buf_size(max_header_size.max(MINIMUM_MAX_HEADER_SIZE));
+            }
+            if let Some(max_headers) = context.max_headers {
+                builder.max_headers(max_headers);
+            }
+            let connection=builder.serve_connection(io, service_clone);
 
-            match with_upgrades
-            {   
-                true=>handle_result(connection.with_upgrades().await),
-                false=>handle_result(connection.await)
+            match context.with_upgrades
+            {
+                true=>handle_result(close_when_idle(connection.with_upgrades(), context.idle_timeout, activity, |connection| connection.graceful_shutdown()).await),
+                false=>handle_result(close_when_idle(connection, context.idle_timeout, activity, |connection| connection.graceful_shutdown()).await)
             };
+
+            if let Some(metrics) = &context.metrics {
+                metrics.connection_closed();
+            }
         }
+        .instrument(tracing::debug_span!("connection", peer = %peer_label(peer_addr)))
     );
 }
 
-fn handle_result<T:std::error::Error>(result:Result<(),T>)->()
+//Starts a graceful shutdown once the connection has seen no traffic for idle_timeout. Requests in flight still complete.
+async fn close_when_idle<C, E>(connection:C, idle_timeout:Option<Duration>, activity:Activity, shutdown:impl Fn(Pin<&mut C>)) -> Result<(), E>
+where
+    C: Future<Output = Result<(), E>>
+{
+    let mut connection = std::pin::pin!(connection);
+    let idle_timeout = match idle_timeout
+    {
+        Some(idle_timeout)=>idle_timeout,
+        None=>return connection.await
+    };
+    loop {
+        let remaining = idle_timeout.saturating_sub(activity.idle_for());
+        tokio::select! {
+            result = connection.as_mut() => return result,
+            _ = tokio::time::sleep(remaining) => {
+                if activity.idle_for() >= idle_timeout {
+                    tracing::debug!(?idle_timeout, "Closing idle connection.");
+                    shutdown(connection.as_mut());
+                    return connection.await;
+                }
+            }
+        }
+    }
+}
+
+fn handle_result<T:std::error::Error>(result:Result<(),T>)
 {
     match result
     {
         Ok(_)=>(),
-        Err(e)=>eprintln!("Listener error {:?}. Could this be a misconfiguration of the service spawner in trm-rust-libs?",e)
+        Err(e)=>tracing::warn!("Listener error {:?}. Could this be a misconfiguration of the service spawner in trm-rust-libs?",e)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        sync::atomic::{AtomicBool, Ordering},
+        task::{Context, Poll},
+        time::Instant,
+    };
+
+    use tokio::io::AsyncWriteExt;
+
+    use super::*;
+
+    //Finishes on its own after lifetime, or right after a graceful shutdown.
+    struct FakeConnection {
+        finished: Pin<Box<tokio::time::Sleep>>,
+        shut_down: Arc<AtomicBool>,
+    }
+
+    impl FakeConnection {
+        fn new(lifetime: Duration) -> FakeConnection {
+            FakeConnection {
+                finished: Box::pin(tokio::time::sleep(lifetime)),
+                shut_down: Arc::new(AtomicBool::new(false)),
+            }
+        }
+    }
+
+    impl Future for FakeConnection {
+        type Output = Result<(), std::io::Error>;
+
+        fn poll(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Self::Output> {
+            match self.shut_down.load(Ordering::SeqCst) {
+                true => Poll::Ready(Ok(())),
+                false => self.finished.as_mut().poll(cx).map(Ok),
+            }
+        }
+    }
+
+    fn shut_down(connection: Pin<&mut FakeConnection>) {
+        connection.shut_down.store(true, Ordering::SeqCst);
+    }
+
+    #[tokio::test]
+    async fn closes_connections_without_traffic() {
+        let connection = FakeConnection::new(Duration::from_secs(10));
+        let shut_down_flag = connection.shut_down.clone();
+        let started = Instant::now();
+        close_when_idle(connection, Some(Duration::from_millis(50)), Activity::new(), shut_down).await.unwrap();
+        assert!(shut_down_flag.load(Ordering::SeqCst));
+        assert!(started.elapsed() < Duration::from_secs(1));
+    }
+
+    #[tokio::test]
+    async fn keeps_busy_connections_open() {
+        let activity = Activity::new();
+        let (mut client, server) = tokio::io::duplex(1024);
+        let mut tracked = ActivityTracked::new(server, activity.clone());
+        tokio::spawn(async move {
+            for _ in 0..20 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                if tracked.write_all(b".").await.is_err() {
+                    break;
+                }
+            }
+        });
+        tokio::spawn(async move { tokio::io::copy(&mut client, &mut tokio::io::sink()).await });
+
+        let connection = FakeConnection::new(Duration::from_millis(150));
+        let shut_down_flag = connection.shut_down.clone();
+        close_when_idle(connection, Some(Duration::from_millis(100)), activity, shut_down).await.unwrap();
+        assert!(!shut_down_flag.load(Ordering::SeqCst));
+
+        let connection = FakeConnection::new(Duration::from_millis(10));
+        close_when_idle(connection, None, Activity::new(), |_| panic!("Shouldn't shut down.")).await.unwrap();
+    }
+}
@@ -0,0 +1,135 @@
+use std::{
+    any::{Any, TypeId},
+    collections::HashMap,
+    sync::{Arc, RwLock},
+};
+
+use hyper::Request;
+
+use crate::commons::HandlerError;
+
+type Entries = HashMap<TypeId, Arc<dyn Any + Send + Sync>>;
+
+//Shared resources keyed by their type, one value per type. Clones share the same values, so a clone kept
+//outside the service works as a control handle while it runs. Values are handed out as Arc snapshots:
+//replacing one affects later lookups, requests already holding the old value keep it.
+//Values that need in-place mutation can bring their own lock, e.g. register a Mutex<T>.
+#[derive(Clone, Default)]
+pub struct AppState {
+    entries: Arc<RwLock<Entries>>,
+}
+
+impl AppState {
+    pub fn new() -> AppState {
+        AppState::default()
+    }
+
+    //For registering resources at construction.
+    pub fn with<T: Send + Sync + 'static>(self, value: T) -> AppState {
+        self.insert(value);
+        self
+    }
+
+    //The state the service put into the request extensions. Empty if the request didn't come through a service.
+    pub fn from_request<B>(request: &Request<B>) -> AppState {
+        request.extensions().get::<AppState>().cloned().unwrap_or_default()
+    }
+
+    pub fn get<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let entry = self.read().get(&TypeId::of::<T>()).cloned()?;
+        entry.downcast::<T>().ok()
+    }
+
+    //Like get, but as an error handlers can return with ?.
+    pub fn require<T: Send + Sync + 'static>(&self) -> Result<Arc<T>, HandlerError> {
+        self.get::<T>()
+            .ok_or_else(|| format!("No {} registered in the application state.", std::any::type_name::<T>()).into())
+    }
+
+    //Returns the value it replaced.
+    pub fn insert<T: Send + Sync + 'static>(&self, value: T) -> Option<Arc<T>> {
+        let previous = self.write().insert(TypeId::of::<T>(), Arc::new(value))?;
+        previous.downcast::<T>().ok()
+    }
+
+    pub fn remove<T: Send + Sync + 'static>(&self) -> Option<Arc<T>> {
+        let previous = self.write().remove(&TypeId::of::<T>())?;
+        previous.downcast::<T>().ok()
+    }
+
+    //Copies the current value, applies the change and swaps the copy in. Returns false if there's no value.
+    //The write lock is held throughout, so concurrent updates aren't lost.
+    pub fn update<T, F>(&self, change: F) -> bool
+    where
+        T: Clone + Send + Sync + 'static,
+        F: FnOnce(&mut T),
+    {
+        let mut entries = self.write();
+        let current = match entries.get(&TypeId::of::<T>()).and_then(|entry| entry.downcast_ref::<T>()) {
+            Some(current) => current,
+            None => return false,
+        };
+        let mut updated = current.clone();
+        change(&mut updated);
+        entries.insert(TypeId::of::<T>(), Arc::new(updated));
+        true
+    }
+
+    //A panic elsewhere can't leave the map half changed, so a poisoned lock is still usable.
+    fn read(&self) -> std::sync::RwLockReadGuard<'_, Entries> {
+        self.entries.read().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn write(&self) -> std::sync::RwLockWriteGuard<'_, Entries> {
+        self.entries.write().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl std::fmt::Debug for AppState {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AppState").field("entries", &self.read().len()).finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use hyper::{body::Incoming, Response, StatusCode};
+
+    use super::*;
+    use crate::{
+        commons::HandlerResult,
+        response_building::bytes_to_boxed_body,
+        service::stateful_service::{StatefulHandler, StatefulService},
+        testing::TestClient,
+    };
+
+    #[derive(Clone)]
+    struct Config {
+        greeting: String,
+    }
+
+    #[derive(Clone)]
+    struct Greeter;
+
+    impl StatefulHandler for Greeter {
+        async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+            let config = AppState::from_request(&request).require::<Config>()?;
+            Ok(Response::new(bytes_to_boxed_body(config.greeting.clone())))
+        }
+    }
+
+    #[tokio::test]
+    async fn handlers_see_state_changed_through_the_handle() {
+        let state = AppState::new().with(Config { greeting: "hello".to_string() });
+        let service = StatefulService::create(Greeter).with_state(state);
+        let handle = service.state_handle();
+        let client = TestClient::new(service);
+        client.get("/").send().await.assert_text("hello");
+
+        assert!(handle.update(|config: &mut Config| config.greeting = "bonjour".to_string()));
+        client.get("/").send().await.assert_text("bonjour");
+
+        handle.remove::<Config>();
+        client.get("/").send().await.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+}
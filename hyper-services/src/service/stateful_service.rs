@@ -2,7 +2,9 @@ use std::net::IpAddr;
 
 use hyper::{body::Incoming, service::Service, Request, Response};
 
-use crate::{commons::{HandlerBody, HandlerError, HandlerFuture, HandlerResult}, service::spawn::{ConnectionProperties, spawn_server}};
+use crate::{commons::{HandlerBody, HandlerError, HandlerFuture, HandlerResult}, service::{guard::{guard, ErrorContext, ErrorHook, PanicHook, ServiceHooks}, listeners::{spawn_listeners, Listener}, spawn::{ConnectionProperties, serve_listener, spawn_server}, state::AppState}};
+#[cfg(unix)]
+use crate::service::{socket_activation::ActivatedListener, unix::{serve_unix_listener, spawn_unix_server, UnixSocketOptions}};
 
 #[trait_variant::make(StatefulHandler: Send)]
 pub trait _LocalStatefulHandler: Clone {
@@ -15,6 +17,8 @@ where
     T: StatefulHandler,
 {
     handler: T,
+    hooks: ServiceHooks,
+    state: AppState,
 }
 
 impl<T> StatefulService<T>
@@ -22,7 +26,30 @@ where
     T: StatefulHandler+'static,
 {
     pub fn create(handler: T) -> StatefulService<T> {
-        StatefulService { handler: handler }
+        StatefulService { handler, hooks: ServiceHooks::default(), state: AppState::default() }
+    }
+
+    //Called for every error returned by the handler, e.g. to log it or redact the problem sent to the client.
+    pub fn with_error_hook(mut self, hook: ErrorHook) -> StatefulService<T> {
+        self.hooks.error_hook = Some(hook);
+        self
+    }
+
+    //Handler panics are answered with server_side_failure and reported here.
+    pub fn with_panic_hook(mut self, hook: PanicHook) -> StatefulService<T> {
+        self.hooks.panic_hook = Some(hook);
+        self
+    }
+
+    //Shared resources for the handler, see AppState::from_request.
+    pub fn with_state(mut self, state: AppState) -> StatefulService<T> {
+        self.state = state;
+        self
+    }
+
+    //Changes made through the handle are seen by requests started afterwards, also while the server runs.
+    pub fn state_handle(&self) -> AppState {
+        self.state.clone()
     }
 
     pub async fn start(
@@ -41,6 +68,73 @@ where
         ).await
     }
 
+    //Serves on a listener bound by the caller, e.g. one kept open across a restart.
+    pub async fn start_with_listener(
+        self,
+        listener: std::net::TcpListener,
+        props: ConnectionProperties
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        listener.set_nonblocking(true)?;
+        serve_listener(
+            tokio::net::TcpListener::from_std(listener)?,
+            self,
+            props
+        ).await
+    }
+
+    //Serves on several addresses at once, each with its own TLS or as a redirect to HTTPS.
+    pub async fn start_listeners(
+        self,
+        listeners: Vec<Listener>,
+        props: ConnectionProperties
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        spawn_listeners(
+            listeners,
+            self,
+            props
+        ).await
+    }
+
+    //Serves on a socket passed by systemd, see socket_activation::activated_sockets.
+    #[cfg(unix)]
+    pub async fn start_activated(
+        self,
+        listener: ActivatedListener,
+        props: ConnectionProperties
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        match listener
+        {
+            ActivatedListener::Tcp(listener)=>self.start_with_listener(listener, props).await,
+            ActivatedListener::Unix(listener)=>{
+                listener.set_nonblocking(true)?;
+                serve_unix_listener(
+                    tokio::net::UnixListener::from_std(listener)?,
+                    self,
+                    props
+                ).await
+            }
+        }
+    }
+
+    //Serves on a Unix domain socket instead of TCP.
+    #[cfg(unix)]
+    pub async fn start_unix(
+        self,
+        options: UnixSocketOptions,
+        props: ConnectionProperties
+    ) -> Result<(), Box<dyn std::error::Error + Send + Sync>>
+    {
+        spawn_unix_server(
+            options,
+            self,
+            props
+        ).await
+    }
+
+    #[deprecated(note = "Only reaches the handler before the service starts. Use with_state and state_handle for shared state.")]
     pub fn get_handler(&mut self)->&mut T
     {
         &mut self.handler
@@ -55,8 +149,10 @@ where
     type Error = HandlerError;
     type Future = HandlerFuture;
 
-    fn call(&self, request: Request<Incoming>) -> Self::Future {
-        Box::pin(T::handle_request(self.handler.clone(), request))
+    fn call(&self, mut request: Request<Incoming>) -> Self::Future {
+        request.extensions_mut().insert(self.state.clone());
+        let context = ErrorContext::from_request(&request);
+        guard(T::handle_request(self.handler.clone(), request), context, self.hooks.clone())
     }
 }
 
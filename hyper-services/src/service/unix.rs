@@ -0,0 +1,253 @@
+use std::{
+    net::SocketAddr,
+    os::unix::fs::{DirBuilderExt, FileTypeExt, PermissionsExt},
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use hyper::{body::Incoming, service::Service, Request, Response};
+use tokio::net::{UnixListener, UnixStream};
+
+use crate::{
+    commons::{HandlerBody, HandlerError, HandlerFuture},
+    service::{
+        connection::{ConnectionInfo, ServerContext, UnixPeerCredentials},
+        spawn::{serve_accepted, tls_acceptor, ConnectionProperties},
+    },
+};
+
+//Where and how the socket file is created.
+#[derive(Debug, Clone)]
+pub struct UnixSocketOptions {
+    pub path: PathBuf,
+    //Permissions of the socket file, e.g. 0o660 so only the owner and group can connect.
+    pub mode: Option<u32>,
+    //Removes a leftover socket file that nothing is listening on anymore.
+    pub remove_stale: bool,
+}
+
+impl UnixSocketOptions {
+    pub fn new<P: Into<PathBuf>>(path: P) -> UnixSocketOptions {
+        UnixSocketOptions {
+            path: path.into(),
+            mode: None,
+            remove_stale: true,
+        }
+    }
+
+    pub fn with_mode(mut self, mode: u32) -> UnixSocketOptions {
+        self.mode = Some(mode);
+        self
+    }
+}
+
+pub(crate) async fn spawn_unix_server<S>(
+    options: UnixSocketOptions,
+    service: S,
+    props: ConnectionProperties,
+) -> Result<(), HandlerError>
+where
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+{
+    let path = options.path.as_path();
+    tracing::info!(path = %path.display(), "Binding unix socket.");
+
+    if options.remove_stale {
+        remove_stale_socket(path).await?;
+    }
+    let listener = match options.mode {
+        Some(mode) => bind_with_mode(path, mode)?,
+        None => UnixListener::bind(path)?,
+    };
+
+    serve_unix_listener(listener, service, props).await
+}
+
+pub(crate) async fn serve_unix_listener<S>(
+    listener: UnixListener,
+    service: S,
+    mut props: ConnectionProperties,
+) -> Result<(), HandlerError>
+where
+    S: 'static + Clone + Send + Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>,
+{
+    let tls_handler = tls_acceptor(props.tls.take(), props.sni.take())?;
+    let peer_addr = props.unix_peer_addr.map(|ip| SocketAddr::new(ip, 0));
+    let context = Arc::new(ServerContext::from_properties(props));
+    let peer_is_trusted_proxy = context.trusts(peer_addr.map(|peer| peer.ip()));
+
+    tracing::info!(address = ?listener.local_addr()?, "Starting listen loop.");
+    loop {
+        match listener.accept().await {
+            Ok((stream, _)) => {
+                let unix_peer = match stream.peer_cred() {
+                    Ok(credentials) => Some(UnixPeerCredentials {
+                        uid: credentials.uid(),
+                        gid: credentials.gid(),
+                        pid: credentials.pid(),
+                    }),
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Couldn't read unix socket peer credentials.");
+                        None
+                    }
+                };
+                let info = ConnectionInfo {
+                    peer_addr,
+                    local_addr: None,
+                    tls: tls_handler.is_some(),
+                    unix_peer,
+                    peer_is_trusted_proxy,
+                };
+                serve_accepted(stream, info, tls_handler.as_ref(), service.clone(), &context).await;
+            }
+            Err(e) => {
+                tracing::warn!(error = %e, "Couldn't accept unix socket connection, retrying.")
+            }
+        }
+    }
+}
+
+//Binds in a directory only the owner can enter and links the socket into place once it has its mode,
+//so nobody can connect while the permissions are still the umask's. Linking fails rather than replacing an existing file.
+fn bind_with_mode(path: &Path, mode: u32) -> Result<UnixListener, HandlerError> {
+    let file_name = path.file_name().ok_or_else(|| format!("{} has no file name.", path.display()))?;
+    let mut private = path.to_path_buf();
+    private.set_file_name(format!(".{}.{}", file_name.to_string_lossy(), std::process::id()));
+    std::fs::DirBuilder::new().mode(0o700).create(&private)?;
+
+    let temporary = private.join(file_name);
+    let bound = UnixListener::bind(&temporary).and_then(|listener| {
+        std::fs::set_permissions(&temporary, std::fs::Permissions::from_mode(mode))?;
+        std::fs::hard_link(&temporary, path)?;
+        Ok(listener)
+    });
+    let _ = std::fs::remove_file(&temporary);
+    let _ = std::fs::remove_dir(&private);
+    Ok(bound?)
+}
+
+//A socket file is only removed if connecting to it fails, so a running server isn't hijacked.
+async fn remove_stale_socket(path: &Path) -> Result<(), HandlerError> {
+    let metadata = match std::fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(Box::new(e)),
+    };
+    if !metadata.file_type().is_socket() {
+        return Err(format!("{} exists and isn't a socket.", path.display()).into());
+    }
+    match UnixStream::connect(path).await {
+        Ok(_) => Err(format!("Another server is listening on {}.", path.display()).into()),
+        Err(_) => {
+            tracing::info!(path = %path.display(), "Removing stale socket file.");
+            std::fs::remove_file(path)?;
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use http_body_util::{BodyExt, Empty};
+    use hyper::body::Bytes;
+    use hyper_util::rt::TokioIo;
+
+    use super::*;
+    use crate::{
+        access_control::AccessControl,
+        commons::HandlerResult,
+        request_processing::client_address::ClientAddr,
+        response_building::bytes_to_boxed_body,
+        service::stateful_service::{StatefulHandler, StatefulService},
+    };
+
+    #[derive(Clone)]
+    struct Credentials;
+
+    impl StatefulHandler for Credentials {
+        async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+            let uid = request.extensions().get::<UnixPeerCredentials>().map(|peer| peer.uid);
+            let client = request.extensions().get::<ClientAddr>().map(|ClientAddr(ip)| *ip);
+            Ok(Response::new(bytes_to_boxed_body(format!("{:?} {:?}", uid, client))))
+        }
+    }
+
+    fn socket_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("hyper-services-{}-{}.sock", name, std::process::id()))
+    }
+
+    //None if the server closes the connection without answering.
+    async fn get(path: &Path) -> Option<String> {
+        let mut stream = None;
+        for _ in 0..50 {
+            match UnixStream::connect(path).await {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(_) => tokio::time::sleep(std::time::Duration::from_millis(20)).await,
+            }
+        }
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(stream.unwrap()))
+            .await
+            .unwrap();
+        tokio::spawn(connection);
+        let request = Request::builder()
+            .uri("/")
+            .header(hyper::header::HOST, "localhost")
+            .body(Empty::<Bytes>::new())
+            .unwrap();
+        let response = sender.send_request(request).await.ok()?;
+        let body = response.into_body().collect().await.unwrap().to_bytes();
+        Some(String::from_utf8(body.to_vec()).unwrap())
+    }
+
+    #[tokio::test]
+    async fn serves_on_unix_socket() {
+        let path = socket_path("serve");
+        //A stale file from an earlier run is replaced.
+        drop(std::os::unix::net::UnixListener::bind(&path));
+
+        let options = UnixSocketOptions::new(&path).with_mode(0o600);
+        tokio::spawn(StatefulService::create(Credentials).start_unix(options, ConnectionProperties::default()));
+        let body = get(&path).await.unwrap();
+
+        let uid = std::fs::metadata(&path).map(|metadata| std::os::unix::fs::MetadataExt::uid(&metadata)).unwrap();
+        assert_eq!(body, format!("Some({}) None", uid));
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o600);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    #[tokio::test]
+    async fn peers_only_have_an_address_when_configured() {
+        let refused = socket_path("refused");
+        let props = ConnectionProperties {
+            access_control: Some(AccessControl::deny_by_default().allow("127.0.0.1").unwrap()),
+            ..Default::default()
+        };
+        tokio::spawn(StatefulService::create(Credentials).start_unix(UnixSocketOptions::new(&refused), props));
+        assert_eq!(get(&refused).await, None);
+
+        let admitted = socket_path("admitted");
+        let props = ConnectionProperties {
+            access_control: Some(AccessControl::deny_by_default().allow("127.0.0.1").unwrap()),
+            unix_peer_addr: Some("127.0.0.1".parse().unwrap()),
+            ..Default::default()
+        };
+        tokio::spawn(StatefulService::create(Credentials).start_unix(UnixSocketOptions::new(&admitted), props));
+        assert!(get(&admitted).await.unwrap().ends_with(" Some(127.0.0.1)"));
+
+        let _ = std::fs::remove_file(&refused);
+        let _ = std::fs::remove_file(&admitted);
+    }
+
+    #[tokio::test]
+    async fn binding_with_mode_never_replaces_files() {
+        let path = socket_path("taken");
+        std::fs::write(&path, "not a socket").unwrap();
+        assert!(bind_with_mode(&path, 0o600).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "not a socket");
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    net::{IpAddr, Ipv4Addr, SocketAddr},
+    sync::Arc,
+};
+
+use http_body_util::{BodyExt, Full};
+use hyper::{
+    body::{Bytes, Incoming},
+    header::{self, HeaderName, HeaderValue},
+    service::Service,
+    HeaderMap, Method, Request, Response, StatusCode,
+};
+use hyper_util::rt::TokioIo;
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    commons::{HandlerBody, HandlerError, HandlerFuture},
+    service::{
+        connection::{ConnectionInfo, ConnectionService, ServerContext},
+        spawn::{service_connection, ConnectionProperties},
+    },
+};
+
+const DUPLEX_BUFFER: usize = 64 * 1024;
+const DEFAULT_PEER: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 40000);
+const DEFAULT_LOCAL: SocketAddr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 8080);
+
+//Drives requests through a service over an in-memory connection, with the same per-connection
+//handling as a listening server: request ids, client address, access control, access log and metrics.
+pub struct TestClient<S> {
+    service: S,
+    context: Arc<ServerContext>,
+    peer_addr: SocketAddr,
+}
+
+impl<S> TestClient<S>
+where
+    S: Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>
+        + Clone
+        + Send
+        + 'static,
+{
+    pub fn new(service: S) -> TestClient<S> {
+        TestClient::with_properties(service, ConnectionProperties::default())
+    }
+
+    //TLS in the properties is ignored.
+    pub fn with_properties(service: S, props: ConnectionProperties) -> TestClient<S> {
+        TestClient {
+            service,
+            context: Arc::new(ServerContext::from_properties(props)),
+            peer_addr: DEFAULT_PEER,
+        }
+    }
+
+    //The address the simulated connection comes from.
+    pub fn with_peer_addr(mut self, peer_addr: SocketAddr) -> TestClient<S> {
+        self.peer_addr = peer_addr;
+        self
+    }
+
+    pub fn request(&self, method: Method, uri: &str) -> TestRequest<'_, S> {
+        TestRequest {
+            client: self,
+            request: Request::builder().method(method).uri(uri),
+            body: Bytes::new(),
+        }
+    }
+
+    pub fn get(&self, uri: &str) -> TestRequest<'_, S> {
+        self.request(Method::GET, uri)
+    }
+
+    pub fn post(&self, uri: &str) -> TestRequest<'_, S> {
+        self.request(Method::POST, uri)
+    }
+
+    pub fn put(&self, uri: &str) -> TestRequest<'_, S> {
+        self.request(Method::PUT, uri)
+    }
+
+    pub fn delete(&self, uri: &str) -> TestRequest<'_, S> {
+        self.request(Method::DELETE, uri)
+    }
+
+    //Every request gets its own connection, like a client that doesn't keep connections alive.
+    pub async fn send(&self, request: Request<Full<Bytes>>) -> Result<Response<Incoming>, HandlerError> {
+        let deferred_access_check = match self.context.admit(Some(self.peer_addr.ip())) {
+            Some(deferred_access_check) => deferred_access_check,
+            None => return Err(format!("Connection from {} was rejected.", self.peer_addr).into()),
+        };
+        let (client_io, server_io) = tokio::io::duplex(DUPLEX_BUFFER);
+        let info = ConnectionInfo {
+            peer_addr: Some(self.peer_addr),
+            local_addr: Some(DEFAULT_LOCAL),
+            tls: false,
+            unix_peer: None,
+            peer_is_trusted_proxy: self.context.trusts(Some(self.peer_addr.ip())),
+        };
+        let service = ConnectionService::new(
+            self.service.clone(),
+            info,
+            self.context.clone(),
+            deferred_access_check,
+        );
+        service_connection(server_io, service, Some(self.peer_addr), self.context.clone()).await;
+
+        let (mut sender, connection) = hyper::client::conn::http1::handshake(TokioIo::new(client_io)).await?;
+        tokio::spawn(async move {
+            if let Err(e) = connection.with_upgrades().await {
+                tracing::debug!(error = %e, "Test connection closed with an error.");
+            }
+        });
+        Ok(sender.send_request(request).await?)
+    }
+}
+
+pub struct TestRequest<'a, S> {
+    client: &'a TestClient<S>,
+    request: hyper::http::request::Builder,
+    body: Bytes,
+}
+
+impl<S> TestRequest<'_, S>
+where
+    S: Service<Request<Incoming>, Response = Response<HandlerBody>, Error = HandlerError, Future = HandlerFuture>
+        + Clone
+        + Send
+        + 'static,
+{
+    pub fn header<K, V>(mut self, name: K, value: V) -> Self
+    where
+        K: TryInto<HeaderName>,
+        K::Error: Into<hyper::http::Error>,
+        V: TryInto<HeaderValue>,
+        V::Error: Into<hyper::http::Error>,
+    {
+        self.request = self.request.header(name, value);
+        self
+    }
+
+    pub fn body<B: Into<Bytes>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    pub fn json<T: Serialize>(self, value: &T) -> Self {
+        let body = serde_json::to_vec(value).expect("Test body should serialize.");
+        self.header(header::CONTENT_TYPE, "application/json").body(body)
+    }
+
+    //Panics if the request can't be sent, since this is only meant for tests.
+    pub async fn send(self) -> TestResponse {
+        let mut request = self
+            .request
+            .body(Full::new(self.body))
+            .expect("Test request should build.");
+        if !request.headers().contains_key(header::HOST) {
+            request
+                .headers_mut()
+                .insert(header::HOST, HeaderValue::from_static("localhost"));
+        }
+        let response = self
+            .client
+            .send(request)
+            .await
+            .expect("Test request should be answered.");
+        TestResponse::collect(response).await
+    }
+}
+
+//A response with its body read to the end.
+#[derive(Debug)]
+pub struct TestResponse {
+    pub status: StatusCode,
+    pub headers: HeaderMap,
+    pub body: Bytes,
+    pub trailers: Option<HeaderMap>,
+}
+
+impl TestResponse {
+    pub async fn collect(response: Response<Incoming>) -> TestResponse {
+        let (parts, body) = response.into_parts();
+        let collected = body.collect().await.expect("Test response body should be read.");
+        TestResponse {
+            status: parts.status,
+            headers: parts.headers,
+            trailers: collected.trailers().cloned(),
+            body: collected.to_bytes(),
+        }
+    }
+
+    pub fn text(&self) -> String {
+        String::from_utf8_lossy(&self.body).to_string()
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        match serde_json::from_slice(&self.body) {
+            Ok(value) => value,
+            Err(e) => panic!("Response body isn't the expected JSON ({}): {}", e, self.text()),
+        }
+    }
+
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers.get(name).and_then(|value| value.to_str().ok())
+    }
+
+    pub fn assert_status(&self, status: StatusCode) -> &TestResponse {
+        assert_eq!(self.status, status, "Unexpected status. Body: {}", self.text());
+        self
+    }
+
+    pub fn assert_header(&self, name: &str, value: &str) -> &TestResponse {
+        assert_eq!(self.header(name), Some(value), "Unexpected {} header.", name);
+        self
+    }
+
+    pub fn assert_text(&self, text: &str) -> &TestResponse {
+        assert_eq!(self.text(), text);
+        self
+    }
+
+    //Compares as JSON values, so formatting and key order don't matter.
+    pub fn assert_json(&self, expected: serde_json::Value) -> &TestResponse {
+        assert_eq!(self.json::<serde_json::Value>(), expected);
+        self
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        access_control::AccessControl,
+        commons::HandlerResult,
+        request_id::HEADER_X_REQUEST_ID,
+        request_processing::{
+            client_address::{ClientAddr, TrustedProxies},
+            get_request_body_as_string,
+        },
+        response_building::bytes_to_boxed_body,
+        service::{
+            access_log::{AccessLog, AccessLogFormat, Rotation},
+            stateful_service::{StatefulHandler, StatefulService},
+        },
+    };
+
+    #[derive(Clone)]
+    struct Echo;
+
+    impl StatefulHandler for Echo {
+        async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+            let client = request.extensions().get::<ClientAddr>().map(|ClientAddr(ip)| ip.to_string());
+            let path = request.uri().path().to_string();
+            if path == "/panic" {
+                panic!("Handler panicked.");
+            }
+            if path == "/fail" {
+                return Err("Handler failed.".into());
+            }
+            if path == "/slow" {
+                tokio::time::sleep(std::time::Duration::from_secs(5)).await;
+            }
+            let body = get_request_body_as_string(request.into_body()).await?;
+            let json = serde_json::json!({ "path": path, "client": client, "body": body });
+            Ok(Response::builder()
+                .header(header::CONTENT_TYPE, "application/json")
+                .body(bytes_to_boxed_body(json.to_string()))?)
+        }
+    }
+
+    #[tokio::test]
+    async fn drives_requests_through_the_connection_service() {
+        let client = TestClient::new(StatefulService::create(Echo));
+        let response = client
+            .post("/items")
+            .header(HEADER_X_REQUEST_ID, "abc")
+            .json(&serde_json::json!({ "a": 1 }))
+            .send()
+            .await;
+        response
+            .assert_status(StatusCode::OK)
+            .assert_header(HEADER_X_REQUEST_ID, "abc")
+            .assert_json(serde_json::json!({ "path": "/items", "client": "127.0.0.1", "body": "{\"a\":1}" }));
+
+        client.get("/panic").send().await.assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+    }
+
+    #[tokio::test]
+    async fn applies_access_control() {
+        let props = ConnectionProperties {
+            access_control: Some(AccessControl::deny_by_default().allow("10.0.0.0/8").unwrap()),
+            ..Default::default()
+        };
+        let client = TestClient::with_properties(StatefulService::create(Echo), props)
+            .with_peer_addr("10.1.2.3:5000".parse().unwrap());
+        client.get("/").send().await.assert_status(StatusCode::OK);
+
+        let denied = client.with_peer_addr("192.168.0.1:5000".parse().unwrap());
+        assert!(denied.send(Request::new(Full::new(Bytes::new()))).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn limits_request_duration() {
+        let props = ConnectionProperties {
+            max_request_duration: Some(std::time::Duration::from_millis(50)),
+            ..Default::default()
+        };
+        let client = TestClient::with_properties(StatefulService::create(Echo), props);
+        client.get("/slow").send().await.assert_status(StatusCode::SERVICE_UNAVAILABLE);
+        client.get("/").send().await.assert_status(StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn logs_failed_and_denied_requests() {
+        let directory = std::env::temp_dir().join(format!("hyper-services-access-log-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&directory);
+        let access_log = AccessLog::new(&directory, "access", AccessLogFormat::Common, Rotation::NEVER, None).unwrap();
+        let props = ConnectionProperties {
+            access_control: Some(AccessControl::deny_by_default().allow("10.0.0.0/8").unwrap()),
+            trusted_proxies: Some(TrustedProxies::new().trust("127.0.0.1/32").unwrap()),
+            access_log: Some(access_log),
+            ..Default::default()
+        };
+        let client = TestClient::with_properties(StatefulService::create(Echo), props);
+        client
+            .get("/fail")
+            .header("x-forwarded-for", "10.1.2.3")
+            .send()
+            .await
+            .assert_status(StatusCode::INTERNAL_SERVER_ERROR);
+        client
+            .get("/")
+            .header("x-forwarded-for", "192.168.0.1")
+            .send()
+            .await
+            .assert_status(StatusCode::FORBIDDEN);
+        //Dropping the log flushes it.
+        drop(client);
+
+        let path = directory.join("access.log");
+        let mut lines = Vec::new();
+        for _ in 0..50 {
+            lines = std::fs::read_to_string(&path).unwrap_or_default().lines().map(|line| line.to_string()).collect();
+            if lines.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(std::time::Duration::from_millis(20)).await;
+        }
+        assert_eq!(lines.len(), 2, "{:?}", lines);
+        assert!(lines.iter().any(|line| line.starts_with("10.1.2.3 ") && line.contains("\"GET /fail HTTP/1.1\" 500 ")));
+        assert!(lines.iter().any(|line| line.starts_with("192.168.0.1 ") && line.contains("\"GET / HTTP/1.1\" 403 ")));
+        let _ = std::fs::remove_dir_all(&directory);
+    }
+}
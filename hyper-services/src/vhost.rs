@@ -0,0 +1,226 @@
+use std::{collections::HashMap, sync::Arc};
+
+use hyper::{body::Incoming, header, http::uri::Authority, Request, StatusCode};
+
+use crate::{
+    commons::{HandlerError, HandlerFuture, HandlerResult},
+    problem::Problem,
+    service::{
+        certificates::{SniResolver, TlsCerts},
+        stateful_service::StatefulHandler,
+    },
+};
+
+type BoxedHandler = Arc<dyn Fn(Request<Incoming>) -> HandlerFuture + Send + Sync>;
+
+//Host names and wildcard patterns like *.example.com, which match exactly one more label, as in certificates:
+//a.example.com but neither example.com nor a.b.example.com. Exact names win over wildcards.
+#[derive(Debug)]
+pub(crate) struct HostTable<T> {
+    exact: HashMap<String, T>,
+    //Keyed by the part after "*.".
+    wildcards: HashMap<String, T>,
+    default: Option<T>,
+}
+
+impl<T> Default for HostTable<T> {
+    fn default() -> Self {
+        HostTable {
+            exact: HashMap::new(),
+            wildcards: HashMap::new(),
+            default: None,
+        }
+    }
+}
+
+impl<T: Clone> Clone for HostTable<T> {
+    fn clone(&self) -> Self {
+        HostTable {
+            exact: self.exact.clone(),
+            wildcards: self.wildcards.clone(),
+            default: self.default.clone(),
+        }
+    }
+}
+
+impl<T> HostTable<T> {
+    //A * is only allowed as the whole first label.
+    pub(crate) fn insert(&mut self, pattern: &str, value: T) -> Result<(), HandlerError> {
+        let pattern = normalize_host(pattern);
+        let (table, key) = match pattern.strip_prefix("*.") {
+            Some(suffix) => (&mut self.wildcards, suffix),
+            None => (&mut self.exact, pattern.as_str()),
+        };
+        if key.is_empty() || key.contains('*') || key.split('.').any(|label| label.is_empty()) {
+            return Err(format!("{} isn't a host name or a wildcard like *.example.com.", pattern).into());
+        }
+        table.insert(key.to_string(), value);
+        Ok(())
+    }
+
+    pub(crate) fn set_default(&mut self, value: T) {
+        self.default = Some(value);
+    }
+
+    //Falls back to the default for unknown or missing hosts.
+    pub(crate) fn get(&self, host: Option<&str>) -> Option<&T> {
+        let found = host.map(normalize_host).and_then(|host| {
+            self.exact.get(&host).or_else(|| {
+                let (label, suffix) = host.split_once('.')?;
+                match label.is_empty() {
+                    true => None,
+                    false => self.wildcards.get(suffix),
+                }
+            })
+        });
+        found.or(self.default.as_ref())
+    }
+}
+
+fn normalize_host(host: &str) -> String {
+    host.trim_end_matches('.').to_ascii_lowercase()
+}
+
+//The host a request is addressed to, without the port. HTTP/2 carries it in :authority, which hyper puts in the URI.
+pub fn request_host<T>(request: &Request<T>) -> Option<String> {
+    let authority = match request.uri().authority() {
+        Some(authority) => authority.clone(),
+        None => request
+            .headers()
+            .get(header::HOST)
+            .and_then(|host| host.to_str().ok())
+            .and_then(|host| host.parse::<Authority>().ok())?,
+    };
+    Some(normalize_host(authority.host()))
+}
+
+//Dispatches requests to a handler by the host they are addressed to. Requests for unknown hosts get the
+//default host's handler, or 421 Misdirected Request if there's none.
+#[derive(Clone, Default)]
+pub struct VirtualHosts {
+    hosts: HostTable<BoxedHandler>,
+    certificates: SniResolver,
+}
+
+impl VirtualHosts {
+    pub fn new() -> VirtualHosts {
+        VirtualHosts::default()
+    }
+
+    //Fails for patterns that are neither a host name nor a wildcard like *.example.com.
+    pub fn host<H>(mut self, pattern: &str, handler: H) -> Result<VirtualHosts, HandlerError>
+    where
+        H: StatefulHandler + Sync + 'static,
+    {
+        self.hosts.insert(pattern, boxed(handler))?;
+        Ok(self)
+    }
+
+    //Also registers the certificate for the host, see sni_resolver.
+    pub fn host_with_tls<H>(mut self, pattern: &str, handler: H, certs: TlsCerts) -> Result<VirtualHosts, HandlerError>
+    where
+        H: StatefulHandler + Sync + 'static,
+    {
+        self.certificates = self.certificates.with_host(pattern, certs)?;
+        self.host(pattern, handler)
+    }
+
+    pub fn default_host<H>(mut self, handler: H) -> VirtualHosts
+    where
+        H: StatefulHandler + Sync + 'static,
+    {
+        self.hosts.set_default(boxed(handler));
+        self
+    }
+
+    //Picks certificates by server name for the hosts added with host_with_tls. Set it as ConnectionProperties::sni.
+    pub fn sni_resolver(&self) -> SniResolver {
+        self.certificates.clone()
+    }
+}
+
+fn boxed<H>(handler: H) -> BoxedHandler
+where
+    H: StatefulHandler + Sync + 'static,
+{
+    Arc::new(move |request| Box::pin(handler.clone().handle_request(request)))
+}
+
+impl StatefulHandler for VirtualHosts {
+    async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+        let host = request_host(&request);
+        match self.hosts.get(host.as_deref()) {
+            Some(handler) => handler(request).await,
+            None => {
+                tracing::debug!(host = ?host, "No virtual host matches the request.");
+                Ok(Problem::new(StatusCode::MISDIRECTED_REQUEST)
+                    .with_detail("This server doesn't serve the requested host.")
+                    .into_response())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        response_building::bytes_to_boxed_body, service::stateful_service::StatefulService, testing::TestClient,
+    };
+
+    #[derive(Clone)]
+    struct Site(&'static str);
+
+    impl StatefulHandler for Site {
+        async fn handle_request(self, _request: Request<Incoming>) -> HandlerResult {
+            Ok(hyper::Response::new(bytes_to_boxed_body(self.0)))
+        }
+    }
+
+    #[test]
+    fn prefers_exact_names_over_wildcards() {
+        let mut table = HostTable::default();
+        table.insert("*.example.com", 1).unwrap();
+        table.insert("*.api.example.com", 2).unwrap();
+        table.insert("www.example.com", 3).unwrap();
+        assert_eq!(table.get(Some("WWW.example.com.")), Some(&3));
+        assert_eq!(table.get(Some("v1.api.example.com")), Some(&2));
+        assert_eq!(table.get(Some("a.example.com")), Some(&1));
+        assert_eq!(table.get(Some("a.b.example.com")), None);
+        assert_eq!(table.get(Some("example.com")), None);
+        assert_eq!(table.get(Some(".example.com")), None);
+        table.set_default(0);
+        assert_eq!(table.get(None), Some(&0));
+        assert_eq!(table.get(Some("x.y.example.com")), Some(&0));
+    }
+
+    #[test]
+    fn rejects_malformed_patterns() {
+        let mut table = HostTable::default();
+        for pattern in ["*example.com", "a.*.example.com", "*.*.example.com", "*.", "*", "", "a..example.com"] {
+            assert!(table.insert(pattern, 1).is_err(), "{}", pattern);
+        }
+    }
+
+    #[tokio::test]
+    async fn dispatches_by_host() {
+        let hosts = VirtualHosts::new()
+            .host("example.com", Site("main"))
+            .unwrap()
+            .host("*.example.com", Site("sub"))
+            .unwrap();
+        let client = TestClient::new(StatefulService::create(hosts.clone()));
+        client.get("/").header(header::HOST, "example.com:8080").send().await.assert_text("main");
+        client.get("/").header(header::HOST, "a.example.com").send().await.assert_text("sub");
+        client.get("http://b.example.com/").send().await.assert_text("sub");
+        client
+            .get("/")
+            .header(header::HOST, "other.org")
+            .send()
+            .await
+            .assert_status(StatusCode::MISDIRECTED_REQUEST);
+
+        let client = TestClient::new(StatefulService::create(hosts.default_host(Site("default"))));
+        client.get("/").header(header::HOST, "other.org").send().await.assert_text("default");
+    }
+}
@@ -0,0 +1,251 @@
+//WebSocket handshakes and sessions for route handlers, over HTTP/1.1 Upgrade (RFC 6455).
+//HTTP/2 extended CONNECT websockets (RFC 8441) are out of scope until the server can serve HTTP/2, since every
+//listener runs hyper's http1 connection builder today.
+
+use std::{future::Future, sync::Arc};
+
+use hyper::{
+    body::Incoming,
+    header::{self, HeaderMap, HeaderValue},
+    http::request::Parts,
+    Method, Request, Response, StatusCode,
+};
+use hyper_tungstenite::tungstenite::{
+    handshake::derive_accept_key,
+    protocol::{Role, WebSocketConfig},
+};
+use hyper_util::rt::TokioIo;
+use tracing::Instrument;
+
+use crate::{
+    commons::{Handler, HandlerBody, HandlerError, HandlerResult},
+    response_building::{bad_request, empty_body},
+};
+
+pub use hyper_tungstenite::{tungstenite::Message, HyperWebsocketStream};
+
+const HEADER_SEC_WEBSOCKET_KEY: &str = "sec-websocket-key";
+const HEADER_SEC_WEBSOCKET_ACCEPT: &str = "sec-websocket-accept";
+const HEADER_SEC_WEBSOCKET_VERSION: &str = "sec-websocket-version";
+const HEADER_SEC_WEBSOCKET_PROTOCOL: &str = "sec-websocket-protocol";
+const WEBSOCKET_VERSION: &str = "13";
+
+//A synchronous check on the request head, like access_control::check_access. Async checks such as
+//check_basic_authentication run in the handler before calling upgrade.
+pub type UpgradeCheck = Arc<dyn Fn(&Parts) -> Handler + Send + Sync>;
+
+//An HTTP/1.1 Upgrade, the only kind the server can receive.
+pub fn is_websocket_request<B>(request: &Request<B>) -> bool {
+    request.method() == Method::GET
+        && header_has_token(request.headers(), header::CONNECTION, "upgrade")
+        && header_has_token(request.headers(), header::UPGRADE, "websocket")
+}
+
+fn header_has_token(headers: &HeaderMap, name: header::HeaderName, token: &str) -> bool {
+    headers
+        .get_all(name)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|value| value.trim().eq_ignore_ascii_case(token))
+}
+
+//Answers the handshake and runs the session on the upgraded connection once the response is sent.
+//Needs ConnectionProperties::with_upgrades.
+#[derive(Clone, Default)]
+pub struct WebSocketUpgrade {
+    config: WebSocketConfig,
+    //Subprotocols in order of preference.
+    protocols: Vec<String>,
+    checks: Vec<UpgradeCheck>,
+}
+
+impl WebSocketUpgrade {
+    pub fn new() -> WebSocketUpgrade {
+        WebSocketUpgrade::default()
+    }
+
+    pub fn with_config(mut self, config: WebSocketConfig) -> WebSocketUpgrade {
+        self.config = config;
+        self
+    }
+
+    pub fn with_max_message_size(mut self, max_message_size: usize) -> WebSocketUpgrade {
+        self.config = self.config.max_message_size(Some(max_message_size));
+        self
+    }
+
+    pub fn with_protocol(mut self, protocol: &str) -> WebSocketUpgrade {
+        self.protocols.push(protocol.to_string());
+        self
+    }
+
+    //Runs in the order added, before anything is upgraded.
+    pub fn with_check(mut self, check: UpgradeCheck) -> WebSocketUpgrade {
+        self.checks.push(check);
+        self
+    }
+
+    //The session gets the request head, including extensions like ClientAddr and AppState.
+    //Requests that aren't websocket handshakes get 426, malformed ones 400.
+    pub fn upgrade<F, Fut>(&self, request: Request<Incoming>, session: F) -> HandlerResult
+    where
+        F: FnOnce(HyperWebsocketStream, Parts) -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), HandlerError>> + Send + 'static,
+    {
+        if !is_websocket_request(&request) {
+            return Ok(Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(header::CONNECTION, "upgrade")
+                .header(header::UPGRADE, "websocket")
+                .body(empty_body())?);
+        }
+
+        let (parts, body) = request.into_parts();
+        for check in &self.checks {
+            match check(&parts) {
+                Handler::Continue => (),
+                Handler::ImmediateReturn(response) => return Ok(response),
+                Handler::Error(e) => return Err(e),
+            }
+        }
+        if parts.headers.get(HEADER_SEC_WEBSOCKET_VERSION).map(|version| version.as_bytes()) != Some(WEBSOCKET_VERSION.as_bytes()) {
+            return Ok(Response::builder()
+                .status(StatusCode::UPGRADE_REQUIRED)
+                .header(HEADER_SEC_WEBSOCKET_VERSION, WEBSOCKET_VERSION)
+                .body(empty_body())?);
+        }
+        let response = match self.handshake_response(&parts) {
+            Some(response) => response,
+            None => return Ok(bad_request()),
+        };
+
+        let mut request = Request::from_parts(parts, body);
+        let on_upgrade = hyper::upgrade::on(&mut request);
+        let (parts, _) = request.into_parts();
+        let config = self.config;
+        tokio::spawn(
+            async move {
+                let upgraded = match on_upgrade.await {
+                    Ok(upgraded) => upgraded,
+                    Err(e) => {
+                        tracing::warn!(error = %e, "Websocket upgrade failed. Are upgrades enabled for the connection?");
+                        return;
+                    }
+                };
+                let stream =
+                    HyperWebsocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Server, Some(config)).await;
+                if let Err(e) = session(stream, parts).await {
+                    tracing::warn!(error = %e, "Websocket session ended with an error.");
+                }
+            }
+            .instrument(tracing::debug_span!("websocket")),
+        );
+        Ok(response)
+    }
+
+    fn handshake_response(&self, parts: &Parts) -> Option<Response<HandlerBody>> {
+        let key = parts.headers.get(HEADER_SEC_WEBSOCKET_KEY)?;
+        let mut builder = Response::builder()
+            .status(StatusCode::SWITCHING_PROTOCOLS)
+            .header(header::CONNECTION, "upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(HEADER_SEC_WEBSOCKET_ACCEPT, derive_accept_key(key.as_bytes()));
+        if let Some(protocol) = self.negotiate_protocol(&parts.headers) {
+            builder = builder.header(HEADER_SEC_WEBSOCKET_PROTOCOL, protocol);
+        }
+        builder.body(empty_body()).ok()
+    }
+
+    fn negotiate_protocol(&self, headers: &HeaderMap) -> Option<HeaderValue> {
+        let offered: Vec<String> = headers
+            .get_all(HEADER_SEC_WEBSOCKET_PROTOCOL)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|protocol| protocol.trim().to_string())
+            .collect();
+        self.protocols
+            .iter()
+            .find(|protocol| offered.contains(protocol))
+            .and_then(|protocol| HeaderValue::from_str(protocol).ok())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::{SinkExt, StreamExt};
+    use http_body_util::Full;
+    use hyper::body::Bytes;
+
+    use super::*;
+    use crate::{
+        access_control::{check_access, AccessControl},
+        service::{
+            spawn::ConnectionProperties,
+            stateful_service::{StatefulHandler, StatefulService},
+        },
+        testing::TestClient,
+    };
+
+    #[derive(Clone)]
+    struct Echo(WebSocketUpgrade);
+
+    impl StatefulHandler for Echo {
+        async fn handle_request(self, request: Request<Incoming>) -> HandlerResult {
+            self.0.upgrade(request, |mut stream, _parts| async move {
+                while let Some(message) = stream.next().await {
+                    let message = message?;
+                    if message.is_text() {
+                        stream.send(message).await?;
+                    }
+                }
+                Ok(())
+            })
+        }
+    }
+
+    fn handshake(uri: &str) -> Request<Full<Bytes>> {
+        Request::builder()
+            .uri(uri)
+            .header(header::HOST, "localhost")
+            .header(header::CONNECTION, "Upgrade")
+            .header(header::UPGRADE, "websocket")
+            .header(HEADER_SEC_WEBSOCKET_KEY, "dGhlIHNhbXBsZSBub25jZQ==")
+            .header(HEADER_SEC_WEBSOCKET_VERSION, "13")
+            .header(HEADER_SEC_WEBSOCKET_PROTOCOL, "chat, superchat")
+            .body(Full::new(Bytes::new()))
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn upgrades_and_runs_the_session() {
+        let props = ConnectionProperties {
+            with_upgrades: true,
+            ..Default::default()
+        };
+        let upgrade = WebSocketUpgrade::new().with_protocol("superchat");
+        let client = TestClient::with_properties(StatefulService::create(Echo(upgrade)), props);
+
+        let response = client.send(handshake("/ws")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::SWITCHING_PROTOCOLS);
+        assert_eq!(response.headers()[HEADER_SEC_WEBSOCKET_ACCEPT], "s3pPLMBiTxaQ9kYGzzhZRbK+xOo=");
+        assert_eq!(response.headers()[HEADER_SEC_WEBSOCKET_PROTOCOL], "superchat");
+
+        let upgraded = hyper::upgrade::on(response).await.unwrap();
+        let mut stream = HyperWebsocketStream::from_raw_socket(TokioIo::new(upgraded), Role::Client, None).await;
+        stream.send(Message::text("hello")).await.unwrap();
+        assert_eq!(stream.next().await.unwrap().unwrap(), Message::text("hello"));
+
+        client.get("/ws").send().await.assert_status(StatusCode::UPGRADE_REQUIRED);
+    }
+
+    #[tokio::test]
+    async fn runs_checks_before_upgrading() {
+        let access_control = AccessControl::deny_by_default();
+        let upgrade = WebSocketUpgrade::new().with_check(Arc::new(move |parts| check_access(parts, &access_control)));
+        let client = TestClient::new(StatefulService::create(Echo(upgrade)));
+        let response = client.send(handshake("/ws")).await.unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    }
+}